@@ -0,0 +1,3 @@
+DROP TABLE locations;
+DROP TABLE tokens;
+DROP TABLE users;
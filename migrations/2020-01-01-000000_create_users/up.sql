@@ -0,0 +1,16 @@
+CREATE TABLE users (
+    name TEXT PRIMARY KEY NOT NULL,
+    password TEXT NOT NULL
+);
+
+CREATE TABLE tokens (
+    token TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL REFERENCES users (name),
+    expire BIGINT NOT NULL
+);
+
+CREATE TABLE locations (
+    name TEXT PRIMARY KEY NOT NULL REFERENCES users (name),
+    x BIGINT NOT NULL,
+    y BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+DROP TABLE invites;
+DROP TABLE members;
+DROP TABLE teams;
@@ -0,0 +1,16 @@
+CREATE TABLE teams (
+    name TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE members (
+    team TEXT NOT NULL REFERENCES teams (name),
+    name TEXT NOT NULL REFERENCES users (name),
+    role INTEGER NOT NULL,
+    PRIMARY KEY (team, name)
+);
+
+CREATE TABLE invites (
+    team TEXT NOT NULL REFERENCES teams (name),
+    name TEXT NOT NULL REFERENCES users (name),
+    PRIMARY KEY (team, name)
+);
@@ -0,0 +1 @@
+DROP TABLE lock_requests;
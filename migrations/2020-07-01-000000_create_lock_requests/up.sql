@@ -0,0 +1,12 @@
+CREATE TABLE lock_requests (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    kind INTEGER NOT NULL,
+    sender TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    x BIGINT NOT NULL,
+    y BIGINT NOT NULL,
+    w INTEGER NOT NULL,
+    h INTEGER NOT NULL
+);
+
+CREATE INDEX lock_requests_recipient ON lock_requests (recipient);
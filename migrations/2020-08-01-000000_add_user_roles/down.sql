@@ -0,0 +1,7 @@
+CREATE TABLE users_without_roles (
+    name TEXT PRIMARY KEY NOT NULL,
+    password TEXT NOT NULL
+);
+INSERT INTO users_without_roles SELECT name, password FROM users;
+DROP TABLE users;
+ALTER TABLE users_without_roles RENAME TO users;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN role INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN banned BOOLEAN NOT NULL DEFAULT 0;
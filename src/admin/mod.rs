@@ -0,0 +1,66 @@
+use actix_web::{
+    error::Result,
+    web,
+    web::{Bytes, Data, Json},
+    HttpRequest, HttpResponse,
+};
+use serde_derive::Serialize;
+
+use std::io::Cursor;
+
+use crate::paint::{self, CacheStats, PaintDB};
+use crate::user::{authorize, UserDB, UserRole};
+
+/// Snapshots are uploaded in a single request body.
+pub const MAX_SNAPSHOT_SIZE: usize = 1 << 30;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/snapshot")
+            .route(web::get().to(export_snapshot))
+            .route(web::put().to(import_snapshot)),
+    )
+    .route("/stats", web::get().to(get_stats));
+}
+
+#[derive(Serialize)]
+struct Stats {
+    cache: CacheStats,
+}
+
+#[derive(Serialize)]
+struct BlockCount {
+    blocks: usize,
+}
+
+async fn export_snapshot(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    authorize(&udb, &req, UserRole::Admin).await?;
+    let mut payload = Vec::<u8>::new();
+    paint::export(&pdb, Cursor::new(&mut payload)).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .body(payload))
+}
+
+async fn import_snapshot(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    body: Bytes,
+) -> Result<Json<BlockCount>> {
+    authorize(&udb, &req, UserRole::Admin).await?;
+    let blocks = paint::import(&pdb, Cursor::new(body)).await?;
+    pdb.compact().await?;
+    Ok(Json(BlockCount { blocks }))
+}
+
+async fn get_stats(udb: Data<UserDB>, pdb: Data<PaintDB>, req: HttpRequest) -> Result<Json<Stats>> {
+    authorize(&udb, &req, UserRole::Admin).await?;
+    Ok(Json(Stats {
+        cache: pdb.cache_stats(),
+    }))
+}
@@ -0,0 +1,97 @@
+use hex::FromHex;
+use rand::Rng;
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::paint::{
+    InternalError, MemStore, PaintConfig, PaintDB, PaintResult, PixelPos, SystemClock, Wal, RGBA,
+};
+use crate::user::Actor;
+
+/// Drawers pick random pixels in a square of this many pixels, i.e. 64 x 64 blocks.
+const CANVAS_SIZE: i64 = 1024;
+const PIXELS_PER_REQUEST: usize = 16;
+
+/// Measure drawing throughput of an in-memory canvas with 1, 2, 4, ... up to
+/// `max_drawers` concurrent drawers, each in its own thread,
+/// without and with an operation log in a temporary directory.
+pub fn run(max_drawers: usize, duration: Duration) -> PaintResult<()> {
+    let mut drawers = 1;
+    loop {
+        let memory = measure(drawers, duration, false)?;
+        let logged = measure(drawers, duration, true)?;
+        println!(
+            "{:>4} drawers: {:>12.0} pixels/s in memory, {:>12.0} pixels/s logged",
+            drawers, memory, logged
+        );
+        if drawers >= max_drawers {
+            return Ok(());
+        }
+        drawers = (drawers * 2).min(max_drawers);
+    }
+}
+
+fn measure(drawers: usize, duration: Duration, logged: bool) -> PaintResult<f64> {
+    let dir = std::env::temp_dir().join(format!("canvast-bench-{}", std::process::id()));
+    let wal = if logged {
+        Some(Wal::open(&dir).map_err(InternalError::from)?)
+    } else {
+        None
+    };
+    let pdb = Arc::new(PaintDB::new(
+        Box::new(MemStore::new()),
+        Arc::new(SystemClock::new()),
+        wal,
+        PaintConfig::default(),
+    ));
+    let start = Instant::now();
+    let deadline = start + duration;
+    let handles: Vec<_> = (0..drawers)
+        .map(|i| {
+            let pdb = pdb.clone();
+            thread::spawn(move || {
+                let mut sys = actix_rt::System::new("canvast-bench");
+                sys.block_on(draw_until(
+                    pdb,
+                    Actor::new(format!("drawer{}", i)),
+                    deadline,
+                ))
+            })
+        })
+        .collect();
+
+    let mut pixels = 0;
+    for handle in handles {
+        pixels += handle.join().expect("drawer panicked")?;
+    }
+    let rate = pixels as f64 / start.elapsed().as_secs_f64();
+    drop(pdb);
+    if logged {
+        fs::remove_dir_all(&dir).map_err(InternalError::from)?;
+    }
+    Ok(rate)
+}
+
+async fn draw_until(pdb: Arc<PaintDB>, user: Actor, deadline: Instant) -> PaintResult<usize> {
+    let color = RGBA::from_hex("1E90FFFF")?;
+    let mut pixels = 0;
+    while Instant::now() < deadline {
+        let start = {
+            let mut rng = rand::thread_rng();
+            PixelPos {
+                x: rng.gen_range(0, CANVAS_SIZE),
+                y: rng.gen_range(0, CANVAS_SIZE),
+            }
+        };
+        // a short horizontal stroke, like a client sends
+        let stroke = (0..PIXELS_PER_REQUEST as i64).map(|dx| PixelPos {
+            x: start.x + dx,
+            y: start.y,
+        });
+        pixels += pdb.draw_pixels(&user, color, stroke).await?;
+    }
+    Ok(pixels)
+}
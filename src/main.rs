@@ -1,37 +1,164 @@
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
 use actix_web::{middleware, web, web::Data, App, HttpServer, Scope};
 use futures::future::ready;
-use lazy_static::lazy_static;
+use log::{error, info};
 
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod admin;
+use admin::MAX_SNAPSHOT_SIZE;
+mod bench;
 mod paint;
-use paint::{now, PaintDB};
+use paint::{BlockStore, FileStore, MemStore, PaintConfig, PaintDB, SystemClock, Wal};
 mod user;
 use user::UserDB;
 
 const APPNAME: &str = "CanVAST";
-
-lazy_static! {
-    static ref UDB: Data<UserDB> = Data::new(UserDB::new());
-    static ref PDB: Data<PaintDB> = Data::new(PaintDB::new());
-}
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60);
+const LEASE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    // init the timer
-    now();
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "canvast=warn,actix_web=info");
     }
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let ["bench", rest @ ..] = args.as_slice() {
+        // runs on a scratch in-memory canvas, the data dir is never touched
+        let drawers = rest.first().and_then(|v| v.parse().ok()).unwrap_or(16);
+        let secs = rest.get(1).and_then(|v| v.parse().ok()).unwrap_or(3);
+        return bench::run(drawers, Duration::from_secs(secs)).map_err(io_error);
+    }
+
+    let data_dir = std::env::var_os("CANVAST_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("data"));
+    std::fs::create_dir_all(&data_dir)?;
+    let (store, wal) = open_store(&data_dir)?;
+    let pdb = Data::new(PaintDB::new(
+        store,
+        Arc::new(SystemClock::new()),
+        wal,
+        PaintConfig::from_env(),
+    ));
+    let replayed = pdb.recover().await.map_err(io_error)?;
+    info!("replayed {} logged operations", replayed);
+
+    match args.as_slice() {
+        [] | ["serve"] => serve(&data_dir, pdb).await,
+        ["export", path] => {
+            let file = BufWriter::new(File::create(path)?);
+            let cnt = paint::export(&pdb, file).await.map_err(io_error)?;
+            println!("exported {} blocks to {}", cnt, path);
+            Ok(())
+        }
+        ["import", path] => {
+            let file = BufReader::new(File::open(path)?);
+            let cnt = paint::import(&pdb, file).await.map_err(io_error)?;
+            pdb.compact().await.map_err(io_error)?;
+            println!("imported {} blocks from {}", cnt, path);
+            Ok(())
+        }
+        _ => {
+            eprintln!("usage: canvast [serve | export <file> | import <file> | bench [drawers] [seconds]]");
+            Err(std::io::ErrorKind::InvalidInput.into())
+        }
+    }
+}
+
+async fn serve(data_dir: &Path, pdb: Data<PaintDB>) -> std::io::Result<()> {
+    let udb = Data::new(open_user_db(data_dir)?);
+    if let Ok(admin) = std::env::var("CANVAST_ADMIN") {
+        let password = std::env::var("CANVAST_ADMIN_PASSWORD").ok();
+        if udb
+            .bootstrap_admin(&admin, password)
+            .await
+            .map_err(io_error)?
+        {
+            info!("{} is an admin", admin);
+        }
+    }
+
+    let compactor = pdb.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(COMPACT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = compactor.compact().await {
+                error!("failed to compact blocks: {}", e);
+            }
+        }
+    });
+
+    let sweeper = pdb.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(LEASE_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match sweeper.sweep_locks().await {
+                Ok(0) => (),
+                Ok(cnt) => info!("released {} expired locks", cnt),
+                Err(e) => error!("failed to sweep locks: {}", e),
+            }
+        }
+    });
+
     let addr = [([0, 0, 0, 0], 8088).into()];
-    HttpServer::new(|| {
+    let server_pdb = pdb.clone();
+    HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
-            .service(api_v0(UDB.clone(), PDB.clone()))
+            .service(api_v0(udb.clone(), server_pdb.clone()))
     })
     .bind(&addr[..])?
     .run()
-    .await
+    .await?;
+
+    match pdb.compact().await {
+        Ok(cnt) => info!("flushed {} blocks", cnt),
+        Err(e) => error!("failed to compact blocks: {}", e),
+    }
+    Ok(())
+}
+
+fn io_error<E: Display>(e: E) -> std::io::Error {
+    std::io::Error::other(e.to_string())
+}
+
+fn open_user_db(data_dir: &Path) -> std::io::Result<UserDB> {
+    let path = data_dir.join("users.sqlite");
+    UserDB::open(&path.to_string_lossy()).map_err(std::io::Error::other)
+}
+
+/// Choose the block store by `CANVAST_STORE`, `file` (the default) or `memory`.
+/// Only the file store keeps an operation log, nothing survives a restart in memory anyway.
+fn open_store(data_dir: &Path) -> std::io::Result<(Box<dyn BlockStore>, Option<Wal>)> {
+    let kind = std::env::var("CANVAST_STORE").unwrap_or_else(|_| "file".to_owned());
+    match kind.as_str() {
+        "file" => Ok((
+            Box::new(FileStore::open(data_dir.join("blocks"))?),
+            Some(Wal::open(data_dir.join("wal"))?),
+        )),
+        "memory" => Ok((Box::new(MemStore::new()), None)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unknown block store: {}", kind),
+        )),
+    }
 }
 
 fn api_v0(udb: Data<UserDB>, pdb: Data<PaintDB>) -> Scope
@@ -44,15 +171,30 @@ where
             web::get().to(|| ready(format!("{} API {}", APPNAME, VERSION))),
         )
         .route("/ping", web::get().to(|| ready("pong")))
+        .service(
+            web::resource("/time")
+                .app_data(pdb.clone())
+                .route(web::get().to(paint::get_time)),
+        )
         .service(
             web::scope("/user")
                 .app_data(udb.clone())
+                .app_data(pdb.clone())
+                .service(web::scope("/admin").configure(user::admin_config))
                 .configure(user::config),
         )
         .service(
             web::scope("/paint")
+                .app_data(udb.clone())
+                .app_data(pdb.clone())
+                .service(web::scope("/admin").configure(paint::admin_config))
+                .configure(paint::config),
+        )
+        .service(
+            web::scope("/admin")
                 .app_data(udb)
                 .app_data(pdb)
-                .configure(paint::config),
+                .app_data(web::PayloadConfig::new(MAX_SNAPSHOT_SIZE))
+                .configure(admin::config),
         )
 }
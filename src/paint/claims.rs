@@ -0,0 +1,418 @@
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+use crate::user::{Actor, Username};
+
+use super::data::{span_fits, BlockPos, PixelPos, BLOCK_BITS, BLOCK_SIZE};
+use super::error::{InternalError, PaintError, PaintResult};
+use super::polygon::{row_spans, FillRule, MAX_POLYGON_POINTS};
+
+/// Longest side of the bounding box of a claim, in pixels.
+const MAX_CLAIM_SIZE: i64 = 256;
+/// Claims an owner may have at once, whatever their area.
+pub const MAX_CLAIMS: usize = 256;
+
+/// Pixels claimed at once.
+/// A pixel is in a polygon if its center is, by the even-odd rule,
+/// so the polygon `(x, y), (x + w, y), (x + w, y + h), (x, y + h)` is the same as the rectangle.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Rect { x: i64, y: i64, w: u32, h: u32 },
+    Polygon(Vec<PixelPos>),
+}
+
+/// Pixels from `(x0, y0)` inclusive to `(x1, y1)` exclusive.
+#[derive(Clone, Copy)]
+struct Bounds {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+}
+
+impl Bounds {
+    fn intersect(self, other: Bounds) -> Option<Bounds> {
+        let b = Bounds {
+            x0: self.x0.max(other.x0),
+            y0: self.y0.max(other.y0),
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+        };
+        if b.x0 < b.x1 && b.y0 < b.y1 {
+            Some(b)
+        } else {
+            None
+        }
+    }
+
+    fn blocks(self) -> impl Iterator<Item = BlockPos> {
+        let (bx0, bx1) = (self.x0 >> BLOCK_BITS, (self.x1 - 1) >> BLOCK_BITS);
+        let (by0, by1) = (self.y0 >> BLOCK_BITS, (self.y1 - 1) >> BLOCK_BITS);
+        (bx0..=bx1).flat_map(move |x| (by0..=by1).map(move |y| BlockPos { x, y }))
+    }
+}
+
+impl Shape {
+    pub fn validate(&self) -> PaintResult<()> {
+        let invalid = |msg: String| Err(PaintError::InvalidData(msg));
+        match self {
+            Shape::Rect { w, h, .. } => {
+                if *w == 0 || *h == 0 {
+                    return invalid("empty rectangle".to_owned());
+                }
+            }
+            Shape::Polygon(points) => {
+                if points.len() < 3 || points.len() > MAX_POLYGON_POINTS {
+                    return invalid(format!(
+                        "a polygon must have 3 to {} points",
+                        MAX_POLYGON_POINTS
+                    ));
+                }
+            }
+        }
+        let b = self.bounds();
+        if !span_fits(b.x0, b.x1, MAX_CLAIM_SIZE) || !span_fits(b.y0, b.y1, MAX_CLAIM_SIZE) {
+            return invalid(format!(
+                "a claim must fit in {0}x{0} pixels",
+                MAX_CLAIM_SIZE
+            ));
+        }
+        if self.area() == 0 {
+            return invalid("claim covers no pixel".to_owned());
+        }
+        Ok(())
+    }
+
+    fn bounds(&self) -> Bounds {
+        match self {
+            Shape::Rect { x, y, w, h } => Bounds {
+                x0: *x,
+                y0: *y,
+                x1: x.saturating_add(*w as i64),
+                y1: y.saturating_add(*h as i64),
+            },
+            Shape::Polygon(points) => Bounds {
+                x0: points.iter().map(|p| p.x).min().unwrap_or(0),
+                y0: points.iter().map(|p| p.y).min().unwrap_or(0),
+                x1: points.iter().map(|p| p.x).max().unwrap_or(0),
+                y1: points.iter().map(|p| p.y).max().unwrap_or(0),
+            },
+        }
+    }
+
+    /// Spans `[x0, x1)` of pixels of the shape on row `y`.
+    fn row(&self, y: i64) -> Vec<(i64, i64)> {
+        let b = self.bounds();
+        if y < b.y0 || y >= b.y1 {
+            return Vec::new();
+        }
+        match self {
+            Shape::Rect { .. } => vec![(b.x0, b.x1)],
+            Shape::Polygon(points) => row_spans(points, y, FillRule::EvenOdd),
+        }
+    }
+
+    pub fn contains(&self, p: PixelPos) -> bool {
+        self.row(p.y).iter().any(|&(x0, x1)| x0 <= p.x && p.x < x1)
+    }
+
+    /// Number of pixels in the shape.
+    pub fn area(&self) -> usize {
+        let b = self.bounds();
+        (b.y0..b.y1)
+            .flat_map(|y| self.row(y))
+            .map(|(x0, x1)| (x1 - x0) as usize)
+            .sum()
+    }
+
+    /// Whether the shapes share any pixel.
+    fn overlaps(&self, other: &Shape) -> bool {
+        let b = match self.bounds().intersect(other.bounds()) {
+            Some(b) => b,
+            None => return false,
+        };
+        (b.y0..b.y1).any(|y| {
+            let theirs = other.row(y);
+            self.row(y)
+                .iter()
+                .any(|&(x0, x1)| theirs.iter().any(|&(ox0, ox1)| x0 < ox1 && ox0 < x1))
+        })
+    }
+
+    /// Blocks the bounding box of the shape covers.
+    pub fn blocks(&self) -> impl Iterator<Item = BlockPos> {
+        self.bounds().blocks()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Claim {
+    pub id: u64,
+    pub owner: Username,
+    pub shape: Shape,
+}
+
+/// Number of claims and pixels claimed by an owner.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct Usage {
+    pub claims: usize,
+    pub pixels: usize,
+}
+
+/// All claims, indexed by the blocks they cover, so that drawing only checks
+/// the claims of the blocks it touches, and summed up by owner.
+#[derive(Default)]
+pub struct ClaimIndex {
+    claims: HashMap<u64, Claim>,
+    blocks: HashMap<BlockPos, Vec<u64>>,
+    owners: HashMap<Username, Usage>,
+    next_id: u64,
+}
+
+impl ClaimIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Id for the next new claim.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Add a claim, or replace the one with the same id.
+    pub fn insert(&mut self, claim: Claim) {
+        self.remove(claim.id);
+        for blk in claim.shape.blocks() {
+            self.blocks.entry(blk).or_default().push(claim.id);
+        }
+        let usage = self.owners.entry(claim.owner.clone()).or_default();
+        usage.claims += 1;
+        usage.pixels += claim.shape.area();
+        self.next_id = self.next_id.max(claim.id + 1);
+        self.claims.insert(claim.id, claim);
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Claim> {
+        let claim = self.claims.remove(&id)?;
+        for blk in claim.shape.blocks() {
+            if let Some(ids) = self.blocks.get_mut(&blk) {
+                ids.retain(|i| *i != id);
+                if ids.is_empty() {
+                    self.blocks.remove(&blk);
+                }
+            }
+        }
+        if let Some(usage) = self.owners.get_mut(&claim.owner) {
+            usage.claims -= 1;
+            usage.pixels -= claim.shape.area();
+            if usage.claims == 0 {
+                self.owners.remove(&claim.owner);
+            }
+        }
+        Some(claim)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Claim> {
+        self.claims.get(&id)
+    }
+
+    fn in_block(&self, blk: BlockPos) -> impl Iterator<Item = &Claim> {
+        self.blocks
+            .get(&blk)
+            .into_iter()
+            .flatten()
+            .map(move |id| &self.claims[id])
+    }
+
+    /// Claims covering any of the blocks, ordered by id.
+    pub fn in_blocks<I: IntoIterator<Item = BlockPos>>(&self, blks: I) -> Vec<&Claim> {
+        let ids: HashSet<u64> = blks
+            .into_iter()
+            .filter_map(|blk| self.blocks.get(&blk))
+            .flatten()
+            .copied()
+            .collect();
+        let mut claims: Vec<&Claim> = ids.iter().map(|id| &self.claims[id]).collect();
+        claims.sort_unstable_by_key(|c| c.id);
+        claims
+    }
+
+    /// Ids of claims not owned by `owner` that share pixels with the shape.
+    pub fn conflicts(&self, owner: &str, shape: &Shape) -> Vec<u64> {
+        self.in_blocks(shape.blocks())
+            .into_iter()
+            .filter(|c| c.owner != owner && c.shape.overlaps(shape))
+            .map(|c| c.id)
+            .collect()
+    }
+
+    /// Ids of the claims of `owner`.
+    pub fn owned(&self, owner: &str) -> Vec<u64> {
+        self.claims
+            .values()
+            .filter(|c| c.owner == owner)
+            .map(|c| c.id)
+            .collect()
+    }
+
+    pub fn usage(&self, owner: &str) -> Usage {
+        self.owners.get(owner).copied().unwrap_or_default()
+    }
+
+    /// Whether any claim covers the block.
+    pub fn claimed(&self, blk: BlockPos) -> bool {
+        self.blocks.contains_key(&blk)
+    }
+
+    /// A claim of others covering the pixel.
+    pub fn blocker(&self, user: &Actor, p: PixelPos) -> Option<&Claim> {
+        if user.overrides {
+            return None;
+        }
+        self.in_block(p.block())
+            .find(|c| !user.owns(&c.owner) && c.shape.contains(p))
+    }
+
+    /// Whether no claim of others covers any pixel of the block.
+    pub fn can_draw_block(&self, user: &Actor, blk: BlockPos) -> bool {
+        self.block_blocker(user, blk).is_none()
+    }
+
+    /// A claim of others covering any pixel of the block.
+    pub fn block_blocker(&self, user: &Actor, blk: BlockPos) -> Option<&Claim> {
+        if user.overrides {
+            return None;
+        }
+        let block = Shape::Rect {
+            x: blk.x << BLOCK_BITS,
+            y: blk.y << BLOCK_BITS,
+            w: BLOCK_SIZE as u32,
+            h: BLOCK_SIZE as u32,
+        };
+        self.in_block(blk)
+            .find(|c| !user.owns(&c.owner) && c.shape.overlaps(&block))
+    }
+
+    pub fn save<W: Write>(&self, w: W) -> Result<(), InternalError> {
+        let claims: Vec<&Claim> = self.claims.values().collect();
+        bincode::serialize_into(w, &(self.next_id, claims))?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(r: R) -> Result<Self, InternalError> {
+        let (next_id, claims): (u64, Vec<Claim>) = bincode::deserialize_from(r)?;
+        let mut this = Self::new();
+        for claim in claims {
+            this.insert(claim);
+        }
+        this.next_id = this.next_id.max(next_id);
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polygon(points: &[(i64, i64)]) -> Shape {
+        Shape::Polygon(points.iter().map(|&(x, y)| PixelPos { x, y }).collect())
+    }
+
+    #[test]
+    fn test_shapes() -> PaintResult<()> {
+        let rect = Shape::Rect {
+            x: -2,
+            y: 0,
+            w: 4,
+            h: 3,
+        };
+        let square = polygon(&[(-2, 0), (2, 0), (2, 3), (-2, 3)]);
+        rect.validate()?;
+        square.validate()?;
+        assert_eq!(rect.area(), 12);
+        assert_eq!(square.area(), 12);
+        for (x, y) in (-4..4).flat_map(|x| (-2..5).map(move |y| (x, y))) {
+            let p = PixelPos { x, y };
+            assert_eq!(rect.contains(p), square.contains(p));
+        }
+
+        // a right triangle covers the pixels whose centers are below its hypotenuse
+        let triangle = polygon(&[(0, 0), (4, 0), (0, 2)]);
+        assert_eq!(triangle.area(), 3 + 1);
+        assert!(triangle.contains(PixelPos { x: 2, y: 0 }));
+        assert!(!triangle.contains(PixelPos { x: 3, y: 0 }));
+        assert!(!triangle.contains(PixelPos { x: 1, y: 1 }));
+        assert!(triangle.overlaps(&rect));
+        let corner = Shape::Rect {
+            x: 3,
+            y: 1,
+            w: 1,
+            h: 1,
+        };
+        assert!(!triangle.overlaps(&corner));
+
+        assert!(polygon(&[(0, 0), (1, 1), (2, 2)]).validate().is_err());
+        assert!(polygon(&[(0, 0), (300, 0), (0, 1)]).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_claim_index() -> Result<(), InternalError> {
+        let mut index = ClaimIndex::new();
+        let claim = |id, owner: &str, shape| Claim {
+            id,
+            owner: owner.to_owned(),
+            shape,
+        };
+        // one pixel apart in the same block
+        index.insert(claim(0, "luffbee", polygon(&[(0, 0), (8, 0), (0, 8)])));
+        index.insert(claim(
+            1,
+            "sam",
+            Shape::Rect {
+                x: 9,
+                y: 0,
+                w: 20,
+                h: 1,
+            },
+        ));
+        let luffbee = Actor::new("luffbee".to_owned());
+        assert!(index.blocker(&luffbee, PixelPos { x: 1, y: 1 }).is_none());
+        assert_eq!(
+            index
+                .blocker(&luffbee, PixelPos { x: 9, y: 0 })
+                .map(|c| c.owner.as_str()),
+            Some("sam")
+        );
+        assert!(index.blocker(&luffbee, PixelPos { x: 8, y: 0 }).is_none());
+        assert!(!index.can_draw_block(&luffbee, BlockPos { x: 0, y: 0 }));
+        assert!(index.claimed(BlockPos { x: 1, y: 0 }));
+        assert_eq!(
+            index.conflicts("luffbee", &polygon(&[(0, 0), (12, 0), (0, 12)])),
+            vec![1]
+        );
+        assert_eq!(
+            index.usage("sam"),
+            Usage {
+                claims: 1,
+                pixels: 20
+            }
+        );
+
+        let mut data = Vec::new();
+        index.save(&mut data)?;
+        let mut index = ClaimIndex::load(&data[..])?;
+        assert_eq!(index.next_id(), 2);
+        assert_eq!(index.in_blocks(vec![BlockPos { x: 1, y: 0 }]).len(), 1);
+        assert_eq!(index.usage("luffbee").pixels, 7 * 8 / 2);
+        index.remove(1);
+        assert_eq!(index.usage("sam"), Usage::default());
+        assert!(!index.claimed(BlockPos { x: 1, y: 0 }));
+        assert!(index.blocker(&luffbee, PixelPos { x: 9, y: 0 }).is_none());
+        assert_eq!(index.next_id(), 2);
+        Ok(())
+    }
+}
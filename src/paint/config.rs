@@ -0,0 +1,112 @@
+use log::warn;
+
+use std::time::Duration;
+
+use crate::user::UserRole;
+
+use super::data::BLOCK_SIZE;
+
+/// Rough memory used by a resident block: pixels, owner, and the map entry.
+const BLOCK_MEMORY: usize = 3 * BLOCK_SIZE * BLOCK_SIZE + 256;
+
+const DEFAULT_CACHE_MB: usize = 256;
+const DEFAULT_MAX_LEASE_SECS: u64 = 24 * 3600;
+const DEFAULT_LOCK_QUOTA: usize = 4096;
+const DEFAULT_CLAIM_QUOTA: usize = 1 << 20;
+/// Moderators and admins get this many times the quotas of users by default.
+const MODERATOR_QUOTA_SCALE: usize = 4;
+const ADMIN_QUOTA_SCALE: usize = 16;
+
+/// Tunables of `PaintDB`.
+#[derive(Clone)]
+pub struct PaintConfig {
+    /// Maximum number of blocks kept in memory.
+    pub cache_blocks: usize,
+    /// Longest time a lock is held without being renewed.
+    pub max_lease: Duration,
+    /// Quotas of each lock owner with the user role, and of teams.
+    pub user_quota: RoleQuota,
+    pub moderator_quota: RoleQuota,
+    pub admin_quota: RoleQuota,
+}
+
+/// Limits of a single lock owner, depending on its role.
+#[derive(Clone, Copy)]
+pub struct RoleQuota {
+    /// Maximum number of blocks locked at once.
+    pub locks: usize,
+    /// Maximum number of pixels claimed.
+    pub pixels: usize,
+}
+
+impl RoleQuota {
+    fn scale(self, n: usize) -> Self {
+        Self {
+            locks: self.locks.saturating_mul(n),
+            pixels: self.pixels.saturating_mul(n),
+        }
+    }
+
+    /// Read `CANVAST_{role}LOCK_QUOTA` and `CANVAST_{role}CLAIM_QUOTA`, falling back to `self`.
+    fn or_env(self, role: &str) -> Self {
+        Self {
+            locks: env_or(&format!("CANVAST_{}LOCK_QUOTA", role), self.locks),
+            pixels: env_or(&format!("CANVAST_{}CLAIM_QUOTA", role), self.pixels),
+        }
+    }
+}
+
+impl PaintConfig {
+    /// Read the config from environment variables, falling back to defaults:
+    /// `CANVAST_CACHE_MB` is the memory budget of the block cache,
+    /// `CANVAST_MAX_LEASE_SECS` the maximum lock lease, `CANVAST_LOCK_QUOTA` the lock quota,
+    /// `CANVAST_CLAIM_QUOTA` the claim quota, of users.
+    /// Quotas of other roles are prefixed, as in `CANVAST_MODERATOR_LOCK_QUOTA`,
+    /// and scale those of users by default.
+    pub fn from_env() -> Self {
+        let cache_mb = env_or("CANVAST_CACHE_MB", DEFAULT_CACHE_MB);
+        let max_lease = env_or("CANVAST_MAX_LEASE_SECS", DEFAULT_MAX_LEASE_SECS);
+        let user_quota = Self::default().user_quota.or_env("");
+        Self {
+            cache_blocks: (cache_mb << 20) / BLOCK_MEMORY,
+            max_lease: Duration::from_secs(max_lease),
+            user_quota,
+            moderator_quota: user_quota.scale(MODERATOR_QUOTA_SCALE).or_env("MODERATOR_"),
+            admin_quota: user_quota.scale(ADMIN_QUOTA_SCALE).or_env("ADMIN_"),
+        }
+    }
+
+    pub fn quota(&self, role: UserRole) -> RoleQuota {
+        match role {
+            UserRole::User => self.user_quota,
+            UserRole::Moderator => self.moderator_quota,
+            UserRole::Admin => self.admin_quota,
+        }
+    }
+}
+
+impl Default for PaintConfig {
+    fn default() -> Self {
+        let user_quota = RoleQuota {
+            locks: DEFAULT_LOCK_QUOTA,
+            pixels: DEFAULT_CLAIM_QUOTA,
+        };
+        Self {
+            cache_blocks: (DEFAULT_CACHE_MB << 20) / BLOCK_MEMORY,
+            max_lease: Duration::from_secs(DEFAULT_MAX_LEASE_SECS),
+            user_quota,
+            moderator_quota: user_quota.scale(MODERATOR_QUOTA_SCALE),
+            admin_quota: user_quota.scale(ADMIN_QUOTA_SCALE),
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            warn!("ignore invalid {}: {}", name, v);
+            default
+        }),
+        Err(_) => default,
+    }
+}
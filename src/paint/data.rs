@@ -1,15 +1,17 @@
 use hex::FromHex;
+use serde::de::Error as _;
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 
-use std::io::{Read, Write};
-use std::ops::Add;
+use std::cmp::{max, min};
+use std::io::{self, Read, Write};
+use std::ops::{Add, RangeInclusive};
 
-use crate::user::Username;
+use crate::user::{Actor, Username};
 
 use super::error::{InternalError, PaintError, PaintResult};
-use super::now;
 
-#[derive(Clone, Copy, Eq, PartialEq, Deserialize, Serialize, Default)]
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Deserialize, Serialize, Default)]
 pub struct PixelPos {
     pub x: i64,
     pub y: i64,
@@ -39,7 +41,7 @@ impl Add<Delta> for PixelPos {
     }
 }
 
-#[derive(Deserialize, Clone, Copy, Hash, Eq, PartialEq, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, Hash, Eq, PartialEq, Debug)]
 pub struct BlockPos {
     pub x: i64,
     pub y: i64,
@@ -55,17 +57,24 @@ impl Add<Offset> for BlockPos {
     }
 }
 
-impl Into<PixelPos> for BlockPos {
-    fn into(self) -> PixelPos {
+impl From<BlockPos> for PixelPos {
+    fn from(blk: BlockPos) -> PixelPos {
         PixelPos {
-            x: self.x << BLOCK_BITS,
-            y: self.y << BLOCK_BITS,
+            x: blk.x << BLOCK_BITS,
+            y: blk.y << BLOCK_BITS,
         }
     }
 }
 
 pub type Offset = (u8, u8);
 
+/// Whether a shape from `lo` to `hi` along an axis lies on the canvas
+/// and `hi - lo` is at most `max`.
+pub fn span_fits<T: Into<i128>>(lo: T, hi: T, max: i64) -> bool {
+    let (lo, hi) = (lo.into(), hi.into());
+    lo >= i64::MIN as i128 && hi <= i64::MAX as i128 && hi - lo <= max as i128
+}
+
 #[derive(Deserialize, Serialize, Clone, Copy)]
 pub struct Delta {
     pub x: i16,
@@ -81,9 +90,7 @@ impl From<Offset> for Delta {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct RGB([u8; 3]);
-#[derive(Clone, Copy)]
+#[derive(Deserialize, Serialize, Clone, Copy)]
 pub struct RGBA([u8; 4]);
 
 impl FromHex for RGBA {
@@ -101,6 +108,14 @@ impl FromHex for RGBA {
 pub const BLOCK_BITS: usize = 4;
 pub const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
 
+/// Pixels from `lo` to `hi` inside block `b` along an axis, both inclusive,
+/// so that the last block, which ends at `i64::MAX`, does not overflow.
+pub fn block_range(b: i64, lo: i64, hi: i64) -> RangeInclusive<i64> {
+    let first = b << BLOCK_BITS;
+    max(lo, first)..=min(hi, first + (BLOCK_SIZE as i64 - 1))
+}
+
+#[derive(Clone)]
 pub struct RGBBlock {
     pixels: Box<[u8; 3 * BLOCK_SIZE * BLOCK_SIZE]>,
 }
@@ -122,7 +137,19 @@ fn pos(x: u8, y: u8) -> usize {
     (BLOCK_SIZE - 1 - (y as usize)) * BLOCK_SIZE + (x as usize)
 }
 
+/// Whether every channel of the colors differs by at most `tolerance`.
+pub fn similar(c1: [u8; 3], c2: [u8; 3], tolerance: u8) -> bool {
+    c1.iter()
+        .zip(c2.iter())
+        .all(|(a, b)| (*a as i16 - *b as i16).abs() <= tolerance as i16)
+}
+
 impl RGBBlock {
+    pub fn pixel(&self, (x, y): Offset) -> [u8; 3] {
+        let idx = 3 * pos(x, y);
+        [self.pixels[idx], self.pixels[idx + 1], self.pixels[idx + 2]]
+    }
+
     pub fn draw_pixels<I>(&mut self, rgba: RGBA, offsets: I)
     where
         I: IntoIterator<Item = Offset>,
@@ -158,6 +185,14 @@ impl RGBBlock {
         write.write_image_data(self.pixels.as_ref())?;
         Ok(())
     }
+
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(self.pixels.as_ref())
+    }
+
+    pub fn load<R: Read>(&mut self, mut r: R) -> io::Result<()> {
+        r.read_exact(self.pixels.as_mut())
+    }
 }
 
 impl Default for RGBBlock {
@@ -168,10 +203,44 @@ impl Default for RGBBlock {
     }
 }
 
+#[derive(Clone)]
 pub struct RGBABlock {
     pixels: Box<[u8; 4 * BLOCK_SIZE * BLOCK_SIZE]>,
 }
 
+impl serde::Serialize for RGBABlock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.pixels.as_ref())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RGBABlock {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: Vec<u8> = serde::Deserialize::deserialize(deserializer)?;
+        let mut this = Self::new();
+        if bytes.len() != this.pixels.len() {
+            return Err(D::Error::invalid_length(bytes.len(), &"RGBA block pixels"));
+        }
+        this.pixels.copy_from_slice(&bytes);
+        Ok(this)
+    }
+}
+
+/// Raw pixels, rows from the top like in a PNG.
+impl FromHex for RGBABlock {
+    type Error = PaintError;
+    fn from_hex<T: AsRef<[u8]>>(hex: T) -> PaintResult<Self> {
+        let mut this = Self::new();
+        match hex::decode_to_slice(hex, this.pixels.as_mut()) {
+            Ok(()) => Ok(this),
+            Err(_) => Err(PaintError::InvalidData(format!(
+                "block image must be {} hex format RGBA pixels",
+                BLOCK_SIZE * BLOCK_SIZE
+            ))),
+        }
+    }
+}
+
 impl RGBABlock {
     #[inline]
     pub fn new() -> Self {
@@ -208,23 +277,186 @@ impl RGBABlock {
     }
 }
 
+/// Lock state of a block.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Lock {
+    /// Empty if the block is not locked.
+    pub owner: Username,
+    /// Remaining lease in milliseconds.
+    pub lease: u64,
+    /// Users allowed to draw on the block besides the owner.
+    pub collaborators: Vec<Username>,
+}
+
+/// Pixels of a block a draw left alone, because of a lock or claims of `owner`.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Rejected {
+    #[serde(flatten)]
+    pub blk: BlockPos,
+    pub owner: Username,
+    pub pixels: usize,
+}
+
+/// Outcome of a draw, rejected pixels are counted once per block and owner.
+#[derive(Default, Debug)]
+pub struct DrawReport {
+    pub drawn: usize,
+    pub rejected: Vec<Rejected>,
+}
+
+impl DrawReport {
+    pub fn reject(&mut self, blk: BlockPos, owner: &str, pixels: usize) {
+        match self
+            .rejected
+            .iter_mut()
+            .find(|r| r.blk == blk && r.owner == owner)
+        {
+            Some(r) => r.pixels += pixels,
+            None => self.rejected.push(Rejected {
+                blk,
+                owner: owner.to_owned(),
+                pixels,
+            }),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct BlockInfo {
     data: RGBBlock,
     owner: Username,
+    /// When the lock expires, in milliseconds since the Unix epoch.
+    expire: u64,
+    /// Granted by the owner, they stay until the lock is released or taken by someone else.
+    collaborators: Vec<Username>,
     mtime: u64,
+    dirty: bool,
 }
 
+pub const MAX_COLLABORATORS: usize = 16;
+
+const BLOCK_MAGIC: &[u8; 3] = b"CVB";
+/// Version 2 adds the lock expiry, version 3 the collaborators, older ones are not read.
+const BLOCK_VERSION: u8 = 3;
+
 impl BlockInfo {
     pub fn new() -> Self {
         Self {
             data: RGBBlock::default(),
             owner: "".to_owned(),
+            expire: 0,
+            collaborators: Vec::new(),
             mtime: 0,
+            dirty: false,
         }
     }
 
-    fn accessable(&self, user: &str) -> bool {
-        self.owner == "" || self.owner == user
+    fn is_locked(&self, now: u64) -> bool {
+        !self.owner.is_empty() && self.expire > now
+    }
+
+    pub fn accessable(&self, user: &str, now: u64) -> bool {
+        !self.is_locked(now) || self.owner == user
+    }
+
+    /// Whether the user may draw on the block: it is not locked,
+    /// the user or one of its teams is the owner, the user is a collaborator,
+    /// or the user draws through locks.
+    pub fn can_draw(&self, user: &Actor, now: u64) -> bool {
+        user.overrides
+            || !self.is_locked(now)
+            || user.owns(&self.owner)
+            || self.collaborators.contains(&user.name)
+    }
+
+    /// Owner of the lock, empty if not locked or the lease has expired.
+    pub fn locked_by(&self, now: u64) -> &str {
+        if self.is_locked(now) {
+            &self.owner
+        } else {
+            ""
+        }
+    }
+
+    pub fn expire(&self) -> u64 {
+        self.expire
+    }
+
+    pub fn collaborators(&self) -> &[Username] {
+        &self.collaborators
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn pixels(&self) -> &RGBBlock {
+        &self.data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
+    }
+
+    /// Serialize the block as: magic, version, mtime, lock expiry, owner length, owner,
+    /// number of collaborators, each with its length, RGB pixels.
+    pub fn save<W: Write>(&self, mut w: W) -> Result<(), InternalError> {
+        w.write_all(BLOCK_MAGIC)?;
+        w.write_all(&[BLOCK_VERSION])?;
+        w.write_all(&self.mtime.to_le_bytes())?;
+        w.write_all(&self.expire.to_le_bytes())?;
+        w.write_all(&[self.owner.len() as u8])?;
+        w.write_all(self.owner.as_bytes())?;
+        w.write_all(&[self.collaborators.len() as u8])?;
+        for user in self.collaborators.iter() {
+            w.write_all(&[user.len() as u8])?;
+            w.write_all(user.as_bytes())?;
+        }
+        self.data.save(w)?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(mut r: R) -> Result<Self, InternalError> {
+        use InternalError::CorruptedBlock;
+
+        let mut header = [0u8; 4];
+        r.read_exact(&mut header)?;
+        if &header[..3] != BLOCK_MAGIC || header[3] != BLOCK_VERSION {
+            return Err(CorruptedBlock);
+        }
+        let mut mtime = [0u8; 8];
+        r.read_exact(&mut mtime)?;
+        let mut expire = [0u8; 8];
+        r.read_exact(&mut expire)?;
+        let mut len = [0u8; 1];
+        r.read_exact(&mut len)?;
+        let mut owner = vec![0u8; len[0] as usize];
+        r.read_exact(&mut owner)?;
+        let mut collaborators = Vec::new();
+        r.read_exact(&mut len)?;
+        for _ in 0..len[0] {
+            let mut n = [0u8; 1];
+            r.read_exact(&mut n)?;
+            let mut user = vec![0u8; n[0] as usize];
+            r.read_exact(&mut user)?;
+            collaborators.push(String::from_utf8(user).map_err(|_| CorruptedBlock)?);
+        }
+
+        let mut this = Self::new();
+        this.mtime = u64::from_le_bytes(mtime);
+        this.owner = String::from_utf8(owner).map_err(|_| CorruptedBlock)?;
+        this.expire = if this.owner.is_empty() {
+            0
+        } else {
+            u64::from_le_bytes(expire)
+        };
+        this.collaborators = collaborators;
+        this.data.load(r)?;
+        Ok(this)
     }
 
     pub fn block_to_png<W: Write>(&self, dst: W, ts: u64) -> PaintResult<u64> {
@@ -234,45 +466,109 @@ impl BlockInfo {
         Ok(self.mtime)
     }
 
-    pub fn draw_pixels<I>(&mut self, user: &str, rgba: RGBA, offsets: I) -> bool
+    pub fn draw_pixels<I>(&mut self, rgba: RGBA, offsets: I, ts: u64)
     where
         I: IntoIterator<Item = Offset>,
     {
-        if self.accessable(user) {
-            self.data.draw_pixels(rgba, offsets);
-            self.mtime = now();
-            return true;
-        }
-        false
+        self.data.draw_pixels(rgba, offsets);
+        self.mtime = ts;
+        self.dirty = true;
     }
 
-    #[allow(dead_code)]
-    pub fn draw_block(&mut self, user: &str, blk: &RGBABlock) -> bool {
-        if self.accessable(user) {
-            self.data.draw_block(blk);
-            self.mtime = now();
-            return true;
-        }
-        false
+    pub fn draw_block(&mut self, blk: &RGBABlock, ts: u64) {
+        self.data.draw_block(blk);
+        self.mtime = ts;
+        self.dirty = true;
     }
 
-    pub fn set_owner(&mut self, user: Username) -> bool {
-        if self.accessable(&user) {
-            self.owner = user;
-            return true;
+    /// Replace the block with a restored one, modified at `ts`.
+    pub fn restore(&mut self, mut info: BlockInfo, ts: u64) {
+        info.mtime = ts;
+        info.dirty = true;
+        *self = info;
+    }
+
+    pub fn set_owner(&mut self, user: Username, expire: u64) {
+        if self.owner != user {
+            self.collaborators.clear();
         }
-        false
+        self.owner = user;
+        self.expire = expire;
+        self.dirty = true;
     }
 
-    pub fn get_owner(&self) -> Username {
-        self.owner.clone()
+    pub fn get_owner(&self) -> &str {
+        &self.owner
     }
 
-    pub fn reset_owner(&mut self, user: &str) -> bool {
-        if self.accessable(&user) {
-            self.owner = "".to_owned();
-            return true;
+    pub fn reset_owner(&mut self) {
+        self.owner = "".to_owned();
+        self.expire = 0;
+        self.collaborators.clear();
+        self.dirty = true;
+    }
+
+    /// Whether the user is not the owner nor a collaborator, and the list is not full.
+    pub fn can_grant(&self, user: &str) -> bool {
+        user != self.owner
+            && self.collaborators.len() < MAX_COLLABORATORS
+            && self.collaborators.iter().all(|c| c != user)
+    }
+
+    /// Allow the user to draw, return false if it can not be granted.
+    pub fn grant(&mut self, user: &str) -> bool {
+        if !self.can_grant(user) {
+            return false;
+        }
+        self.collaborators.push(user.to_owned());
+        self.dirty = true;
+        true
+    }
+
+    /// Return false if the user was not a collaborator.
+    pub fn revoke(&mut self, user: &str) -> bool {
+        let len = self.collaborators.len();
+        self.collaborators.retain(|c| c != user);
+        if self.collaborators.len() == len {
+            return false;
         }
-        false
+        self.dirty = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_versions() -> Result<(), InternalError> {
+        // older versions are refused
+        let mut v1 = b"CVB\x01".to_vec();
+        v1.extend_from_slice(&7u64.to_le_bytes());
+        v1.push(3);
+        v1.extend_from_slice(b"bob");
+        v1.extend_from_slice(&[255; 3 * BLOCK_SIZE * BLOCK_SIZE]);
+        assert!(BlockInfo::load(&v1[..]).is_err());
+
+        let mut info = BlockInfo::new();
+        info.set_owner("bob".to_owned(), 42);
+        assert!(info.grant("sam"));
+        assert!(!info.grant("sam"));
+        assert!(!info.grant("bob"));
+        let mut v3 = Vec::new();
+        info.save(&mut v3)?;
+        let mut loaded = BlockInfo::load(&v3[..])?;
+        assert_eq!((loaded.get_owner(), loaded.expire()), ("bob", 42));
+        assert_eq!(loaded.collaborators(), ["sam".to_owned()]);
+        assert!(loaded.accessable("bob", 41));
+        assert!(!loaded.accessable("sam", 41));
+        assert!(loaded.can_draw(&Actor::new("sam".to_owned()), 41));
+        assert!(!loaded.can_draw(&Actor::new("tom".to_owned()), 41));
+        assert!(loaded.accessable("tom", 42));
+
+        loaded.set_owner("tom".to_owned(), 100);
+        assert!(loaded.collaborators().is_empty());
+        Ok(())
     }
 }
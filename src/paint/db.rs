@@ -1,23 +1,45 @@
-use async_trait::async_trait;
-use parking_lot::RwLock;
+use lazy_static::lazy_static;
+use log::error;
+use parking_lot::{Mutex as SyncMutex, RwLock};
+use serde_derive::Serialize;
 use tokio::sync::{watch, Mutex};
 
-use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, Entry as MapEntry};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::ops::FnOnce;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::user::Username;
+use crate::user::{Actor, UserRole, Username};
 
+use super::claims::{Claim, ClaimIndex, Shape, MAX_CLAIMS};
+use super::config::PaintConfig;
 use super::data::Delta;
 use super::data::*;
-use super::line::LineIter;
+use super::ellipse::Ellipse;
+use super::line::{stroke, LineStyle};
+use super::locks::LockIndex;
+use super::polygon::{FillRule, Polygon};
+use super::rect::PixelRect;
+use super::store::BlockStore;
+use super::timestamp::Clock;
+use super::wal::{Op, Wal};
 use super::PaintResult;
 
-use super::error::InternalError;
+use super::error::{InternalError, PaintError};
 
-#[async_trait]
-pub trait AsyncProc<T> {
-    async fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T>;
+pub trait BlockProc<T>: Sized {
+    fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T>;
+    /// Process a block that has never been written, without creating it.
+    /// Give the procedure back if it needs a real block.
+    fn call_empty(self) -> Result<PaintResult<T>, Self>;
+}
+
+lazy_static! {
+    /// Shared by all reads of blocks that have never been written.
+    static ref EMPTY_BLOCK: BlockInfo = BlockInfo::new();
 }
 
 struct ReadProc<F, T: 'static>(F)
@@ -27,33 +49,132 @@ struct WriteProc<F, T: 'static>(F)
 where
     F: FnOnce(&mut BlockInfo) -> PaintResult<T> + Send;
 
-#[async_trait]
-impl<F, T: 'static> AsyncProc<T> for ReadProc<F, T>
+impl<F, T: 'static> BlockProc<T> for ReadProc<F, T>
 where
     F: FnOnce(&BlockInfo) -> PaintResult<T> + Send,
 {
-    async fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T> {
+    fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T> {
         let read = lock.read();
         self.0(&read)
     }
+
+    fn call_empty(self) -> Result<PaintResult<T>, Self> {
+        Ok(self.0(&EMPTY_BLOCK))
+    }
 }
 
-#[async_trait]
-impl<F, T: 'static> AsyncProc<T> for WriteProc<F, T>
+impl<F, T: 'static> BlockProc<T> for WriteProc<F, T>
 where
     F: FnOnce(&mut BlockInfo) -> PaintResult<T> + Send,
 {
-    async fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T> {
+    fn call(self, lock: &RwLock<BlockInfo>) -> PaintResult<T> {
         let mut write = lock.write();
         self.0(&mut write)
     }
+
+    fn call_empty(self) -> Result<PaintResult<T>, Self> {
+        Err(self)
+    }
 }
 
-pub struct PaintDB {
-    blocks: RwLock<HashMap<BlockPos, RwLock<BlockInfo>>>,
+/// A resident block.
+struct Entry {
+    info: RwLock<BlockInfo>,
+    /// Tick of the last access, for LRU eviction.
+    used: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub resident: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+const SHARDS: usize = 64;
+
+fn shard_index(blk: BlockPos) -> usize {
+    let mut hasher = DefaultHasher::new();
+    blk.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// A part of the block index, blocks are spread over shards by the hash of their positions,
+/// so that accesses to different blocks rarely contend for a lock.
+struct Shard {
+    blocks: RwLock<HashMap<BlockPos, Entry>>,
     loading: Mutex<HashMap<BlockPos, watch::Receiver<()>>>,
 }
 
+impl Shard {
+    fn new() -> Self {
+        Self {
+            blocks: RwLock::new(HashMap::new()),
+            loading: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+pub struct PaintDB {
+    shards: Vec<Shard>,
+    /// Number of blocks in all shards.
+    resident: AtomicUsize,
+    /// Held while saving blocks, so that a block is never evicted
+    /// before its latest version reaches the store.
+    write_back: Mutex<()>,
+    store: Box<dyn BlockStore>,
+    clock: Arc<dyn Clock>,
+    wal: Option<Wal>,
+    config: PaintConfig,
+    counters: CacheCounters,
+    /// Updated together with logging lock operations, so that a checkpoint covers
+    /// every lock operation logged before it.
+    locks: SyncMutex<LockIndex>,
+    /// Written together with logging claim operations, for the same reason.
+    /// Read while holding block locks to check drawings, never the other way around.
+    claims: RwLock<ClaimIndex>,
+}
+
+/// An operation of a batch, lines are given by their pixels.
+pub enum BatchOp {
+    Pixels { color: RGBA, pixels: Vec<PixelPos> },
+    Block { blk: BlockPos, image: RGBABlock },
+}
+
+/// Largest region of a flood fill, and most blocks it may look at.
+const MAX_FILL_PIXELS: usize = 1 << 16;
+const MAX_FILL_BLOCKS: usize = 1024;
+/// Times a flood fill is recomputed when blocks change under it.
+const FILL_RETRIES: usize = 3;
+
+/// A block as a flood fill saw it.
+struct FillBlock {
+    mtime: u64,
+    /// Pixels, or the lock owner if the user may not draw on the block.
+    pixels: Result<RGBBlock, Username>,
+    /// Pixels claimed by others, with the owners of the claims.
+    claimed: HashMap<Offset, Username>,
+}
+
+#[derive(Serialize)]
+pub struct Quota {
+    pub quota: usize,
+    pub locked: usize,
+    pub available: usize,
+}
+
 impl PaintDB {
     async fn write_block<F, T: 'static>(&self, blk: BlockPos, proc: F) -> PaintResult<T>
     where
@@ -71,13 +192,15 @@ impl PaintDB {
 
     async fn process_block<F, T: 'static>(&self, blk: BlockPos, proc: F) -> PaintResult<T>
     where
-        F: AsyncProc<T>,
+        F: BlockProc<T>,
     {
+        let shard = self.shard(blk);
         let mut proc = Some(proc);
         let mut retry_limit = 3;
         loop {
-            if let Some(p) = self.blocks.read().get(&blk) {
-                return proc.unwrap().call(p).await;
+            if let Some(entry) = shard.blocks.read().get(&blk) {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return proc.unwrap().call(self.touch(entry));
             }
 
             if retry_limit > 0 {
@@ -86,23 +209,47 @@ impl PaintDB {
                 return Err(InternalError::BlockLoadLimitExceeded.into());
             }
 
-            match self.load_block(blk, proc.unwrap()).await? {
-                Ok(ret) => return Ok(ret),
+            match self.load_block(shard, blk, proc.unwrap()).await? {
+                Ok(ret) => {
+                    if self.resident.load(Ordering::Relaxed) > self.config.cache_blocks {
+                        if let Err(e) = self.evict().await {
+                            error!("failed to evict blocks: {}", e);
+                        }
+                    }
+                    return Ok(ret);
+                }
                 Err(p) => proc = Some(p),
             }
         }
     }
 
-    async fn load_block<F, T: 'static>(&self, blk: BlockPos, proc: F) -> PaintResult<Result<T, F>>
+    fn shard(&self, blk: BlockPos) -> &Shard {
+        &self.shards[shard_index(blk)]
+    }
+
+    fn touch<'a>(&self, entry: &'a Entry) -> &'a RwLock<BlockInfo> {
+        let tick = self.counters.tick.fetch_add(1, Ordering::Relaxed);
+        entry.used.store(tick, Ordering::Relaxed);
+        &entry.info
+    }
+
+    async fn load_block<F, T: 'static>(
+        &self,
+        shard: &Shard,
+        blk: BlockPos,
+        proc: F,
+    ) -> PaintResult<Result<T, F>>
     where
-        F: AsyncProc<T>,
+        F: BlockProc<T>,
     {
-        let mut loading = self.loading.lock().await;
+        let mut loading = shard.loading.lock().await;
         match loading.get(&blk) {
             Some(rx) => {
                 let mut rx = rx.clone();
                 drop(loading);
-                rx.recv().await;
+                // the first recv returns the initial value at once,
+                // the loading is done when the sender is dropped
+                while rx.recv().await.is_some() {}
                 Ok(Err(proc))
             }
             None => {
@@ -110,45 +257,467 @@ impl PaintDB {
                 loading.insert(blk, rx);
                 drop(loading);
 
-                let ret;
+                let loaded = match shard.blocks.read().get(&blk) {
+                    Some(entry) => Ok(proc.call(self.touch(entry))),
+                    None => Err(proc),
+                };
+                let ret = match loaded {
+                    Ok(ret) => ret,
+                    Err(proc) => match self.store.load(blk).await {
+                        Ok(Some(info)) => {
+                            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                            self.clock.observe(info.mtime());
+                            self.insert_block(shard, blk, info, proc)
+                        }
+                        Ok(None) => {
+                            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                            // only materialize empty space when it is written
+                            match proc.call_empty() {
+                                Ok(ret) => ret,
+                                Err(proc) => self.insert_block(shard, blk, BlockInfo::new(), proc),
+                            }
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+
+                shard.loading.lock().await.remove(&blk);
+                drop(tx); // wake up all waiters
+
+                ret.map(Ok)
+            }
+        }
+    }
+
+    fn insert_block<F, T>(
+        &self,
+        shard: &Shard,
+        blk: BlockPos,
+        info: BlockInfo,
+        proc: F,
+    ) -> PaintResult<T>
+    where
+        F: BlockProc<T>,
+    {
+        // insert before processing, so that a compaction never misses
+        // an operation logged on this block
+        let mut blocks = shard.blocks.write();
+        let entry = match blocks.entry(blk) {
+            MapEntry::Occupied(entry) => entry.into_mut(),
+            MapEntry::Vacant(entry) => {
+                self.resident.fetch_add(1, Ordering::Relaxed);
+                entry.insert(Entry {
+                    info: RwLock::new(info),
+                    used: AtomicU64::new(0),
+                })
+            }
+        };
+        proc.call(self.touch(entry))
+    }
+
+    /// Process several blocks at once, all of them are locked for writing during `proc`.
+    ///
+    /// Blocks are locked in the order of their positions, so concurrent calls never deadlock;
+    /// `proc` gets them in that order, without duplicates.
+    /// At most half of the cache is taken at once, so that loading them does not evict them.
+    async fn write_blocks<F, T>(&self, blks: &[BlockPos], proc: F) -> PaintResult<T>
+    where
+        F: FnOnce(&mut [(BlockPos, &mut BlockInfo)]) -> PaintResult<T>,
+    {
+        let mut blks = blks.to_vec();
+        blks.sort_unstable_by_key(|blk| (blk.x, blk.y));
+        blks.dedup();
+        let max_blocks = (self.config.cache_blocks / 2).max(1);
+        if blks.len() > max_blocks {
+            return Err(PaintError::TooManyBlocks(max_blocks));
+        }
 
-                let blocks_read = self.blocks.read();
-                if let Some(block) = blocks_read.get(&blk) {
-                    self.loading.lock().await.remove(&blk);
-                    let _ = tx.broadcast(()); // error only when no receiver
+        let mut retry_limit = 3;
+        loop {
+            // make sure all blocks are resident, an eviction may still race with us
+            for blk in blks.iter() {
+                self.write_block(*blk, |_| Ok(())).await?;
+            }
 
-                    ret = proc.call(&block).await;
-                } else {
-                    drop(blocks_read); // prevent deadlock with later write
+            // a shard is read locked at most once, and recursively, so a waiting
+            // writer can not block us halfway
+            let mut shards: Vec<Option<_>> = (0..SHARDS).map(|_| None).collect();
+            for blk in blks.iter() {
+                let i = shard_index(*blk);
+                if shards[i].is_none() {
+                    shards[i] = Some(self.shards[i].blocks.read_recursive());
+                }
+            }
+            let mut infos = Vec::with_capacity(blks.len());
+            for blk in blks.iter() {
+                let blocks = shards[shard_index(*blk)].as_ref().unwrap();
+                match blocks.get(blk) {
+                    Some(entry) => infos.push((*blk, self.touch(entry).write())),
+                    None => break,
+                }
+            }
 
-                    let block = RwLock::new(BlockInfo::new());
-                    ret = proc.call(&block).await;
-                    self.blocks.write().insert(blk, block);
+            if infos.len() == blks.len() {
+                let mut infos: Vec<_> = infos.iter_mut().map(|(blk, g)| (*blk, &mut **g)).collect();
+                return proc(&mut infos);
+            }
+            if retry_limit > 0 {
+                retry_limit -= 1;
+            } else {
+                return Err(InternalError::BlockLoadLimitExceeded.into());
+            }
+        }
+    }
+
+    /// Drop the least recently used blocks until the cache is a bit under its capacity,
+    /// dirty ones are saved first. Return the number of evicted blocks.
+    async fn evict(&self) -> PaintResult<usize> {
+        // someone else is saving blocks, let the next load try again
+        let _guard = match self.write_back.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(0),
+        };
 
-                    self.loading.lock().await.remove(&blk);
-                    let _ = tx.broadcast(()); // error only when no receiver
+        let capacity = self.config.cache_blocks;
+        let low = capacity - capacity / 16;
+        if self.resident.load(Ordering::Relaxed) <= capacity {
+            return Ok(0);
+        }
+        let mut victims: Vec<(u64, BlockPos)> = Vec::new();
+        for shard in self.shards.iter() {
+            let blocks = shard.blocks.read();
+            victims.extend(
+                blocks
+                    .iter()
+                    .map(|(blk, entry)| (entry.used.load(Ordering::Relaxed), *blk)),
+            );
+        }
+        let cnt = victims.len().saturating_sub(low);
+        victims.sort_unstable_by_key(|(used, _)| *used);
+        victims.truncate(cnt);
+
+        let dirty: Vec<(BlockPos, BlockInfo)> = victims
+            .iter()
+            .filter_map(|(_, blk)| {
+                let blocks = self.shard(*blk).blocks.read();
+                let mut info = blocks.get(blk)?.info.write();
+                if !info.is_dirty() {
+                    return None;
                 }
+                info.set_dirty(false);
+                Some((*blk, info.clone()))
+            })
+            .collect();
+        let result = self.save_blocks(dirty).await;
+        self.counters
+            .write_backs
+            .fetch_add(*result.as_ref().unwrap_or(&0) as u64, Ordering::Relaxed);
 
-                ret.map(Ok)
+        // nobody holds a block while its shard is locked for writing; skip blocks
+        // used or modified since they were picked, and those failed to be saved
+        let mut evicted = 0;
+        for (used, blk) in victims {
+            let mut blocks = self.shard(blk).blocks.write();
+            let stale = match blocks.get(&blk) {
+                Some(entry) => {
+                    entry.used.load(Ordering::Relaxed) == used && !entry.info.read().is_dirty()
+                }
+                None => false,
+            };
+            if stale {
+                blocks.remove(&blk);
+                evicted += 1;
+            }
+        }
+        self.resident.fetch_sub(evicted, Ordering::Relaxed);
+        self.counters
+            .evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+        result.map(|_| evicted)
+    }
+
+    /// Save blocks to the store, blocks failed to be saved are marked dirty again.
+    /// Return the number of saved blocks.
+    async fn save_blocks(&self, blocks: Vec<(BlockPos, BlockInfo)>) -> PaintResult<usize> {
+        let mut saved = 0;
+        let mut result = Ok(());
+        for (blk, info) in blocks {
+            match self.store.save(blk, info).await {
+                Ok(()) => saved += 1,
+                Err(e) => {
+                    // keep it dirty so that the next flush will retry
+                    if let Some(entry) = self.shard(blk).blocks.read().get(&blk) {
+                        entry.info.write().set_dirty(true);
+                    }
+                    result = Err(e);
+                }
             }
         }
+        result.map(|_| saved)
     }
 }
 
 impl PaintDB {
-    pub fn new() -> Self {
+    pub fn new(
+        store: Box<dyn BlockStore>,
+        clock: Arc<dyn Clock>,
+        wal: Option<Wal>,
+        mut config: PaintConfig,
+    ) -> Self {
+        config.cache_blocks = config.cache_blocks.max(1);
         Self {
-            blocks: RwLock::new(HashMap::new()),
-            loading: Mutex::new(HashMap::new()),
+            shards: (0..SHARDS).map(|_| Shard::new()).collect(),
+            resident: AtomicUsize::new(0),
+            write_back: Mutex::new(()),
+            store,
+            clock,
+            wal,
+            config,
+            counters: CacheCounters::default(),
+            locks: SyncMutex::new(LockIndex::new()),
+            claims: RwLock::new(ClaimIndex::new()),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        CacheStats {
+            capacity: self.config.cache_blocks,
+            resident: self.resident.load(Ordering::Relaxed),
+            hits: load(&self.counters.hits),
+            misses: load(&self.counters.misses),
+            evictions: load(&self.counters.evictions),
+            write_backs: load(&self.counters.write_backs),
+        }
+    }
+
+    /// Wait until every operation logged so far is written, before a change is acknowledged.
+    /// Operations are logged and applied under block locks, then waited for without them.
+    async fn logged(&self) -> PaintResult<()> {
+        if let Some(wal) = &self.wal {
+            wal.written().await?;
         }
+        Ok(())
     }
-    pub async fn draw_pixels<I>(&self, user: &str, color: RGBA, pixels: I) -> PaintResult<usize>
+
+    /// Log an operation, then apply it.
+    fn commit(&self, info: &mut BlockInfo, op: Op) -> PaintResult<()> {
+        if !op.is_lock() {
+            if let Some(wal) = &self.wal {
+                wal.append(std::slice::from_ref(&op))?;
+            }
+            op.apply(info);
+            return Ok(());
+        }
+        let blk = op.block().expect("lock operation without a block");
+        self.commit_all(&mut self.locks.lock(), &mut [(blk, info)], vec![op])
+    }
+
+    /// Log operations as a single record, so that they are replayed all or none, then apply them.
+    /// `blocks` must be sorted by position and hold the block of every operation,
+    /// `index` is the locked lock index.
+    fn commit_all(
+        &self,
+        index: &mut LockIndex,
+        blocks: &mut [(BlockPos, &mut BlockInfo)],
+        ops: Vec<Op>,
+    ) -> PaintResult<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        if let Some(wal) = &self.wal {
+            wal.append(&ops)?;
+        }
+        for op in ops {
+            let blk = op.block().expect("claims are not committed with blocks");
+            let i = blocks
+                .binary_search_by_key(&(blk.x, blk.y), |(b, _)| (b.x, b.y))
+                .expect("operation on a block not locked");
+            op.apply(blocks[i].1);
+            if op.is_lock() {
+                index.update(blk, blocks[i].1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a claim operation, then apply it, `claims` is the locked claim index.
+    fn commit_claim(&self, claims: &mut ClaimIndex, op: Op) -> PaintResult<()> {
+        if let Some(wal) = &self.wal {
+            wal.append(std::slice::from_ref(&op))?;
+        }
+        op.apply_claims(claims);
+        Ok(())
+    }
+
+    /// Load the lock and claim indexes from the last checkpoint,
+    /// or build the lock index from the block store if there is none yet.
+    async fn load_checkpoint(&self, wal: &Wal) -> PaintResult<()> {
+        let (index, claims) = match wal.load_checkpoint().map_err(InternalError::from)? {
+            Some(data) => {
+                let mut r = &data[..];
+                (LockIndex::load(&mut r)?, ClaimIndex::load(&mut r)?)
+            }
+            None => {
+                let mut index = LockIndex::new();
+                for blk in self.store.list().await? {
+                    if let Some(info) = self.store.load(blk).await? {
+                        index.update(blk, &info);
+                    }
+                }
+                (index, ClaimIndex::new())
+            }
+        };
+        *self.locks.lock() = index;
+        *self.claims.write() = claims;
+        Ok(())
+    }
+
+    /// Replay the operation log on top of the block store, must be called before serving.
+    pub async fn recover(&self) -> PaintResult<usize> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(0),
+        };
+        self.load_checkpoint(wal).await?;
+        let ids = wal.rotate().map_err(InternalError::from)?;
+        let ops = wal.read(&ids)?;
+        for op in ops.iter() {
+            let blk = match op.block() {
+                Some(blk) => blk,
+                None => {
+                    op.apply_claims(&mut self.claims.write());
+                    continue;
+                }
+            };
+            let mtime = self
+                .write_block(blk, |info| {
+                    if !op.applied(info) {
+                        op.apply(info);
+                    }
+                    if op.is_lock() {
+                        self.locks.lock().update(blk, info);
+                    }
+                    Ok(info.mtime())
+                })
+                .await?;
+            self.clock.observe(mtime);
+        }
+        self.compact().await?;
+        Ok(ops.len())
+    }
+
+    /// Fold the operation log into the block store.
+    pub async fn compact(&self) -> PaintResult<usize> {
+        let ids = match &self.wal {
+            Some(wal) => wal.rotate().map_err(InternalError::from)?,
+            None => Vec::new(),
+        };
+        // every operation in the old segments is applied before its block lock is released
+        let cnt = self.flush().await?;
+        if let Some(wal) = &self.wal {
+            let mut checkpoint = Vec::new();
+            self.locks.lock().save(&mut checkpoint)?;
+            self.claims.read().save(&mut checkpoint)?;
+            wal.save_checkpoint(&checkpoint)
+                .map_err(InternalError::from)?;
+            wal.remove(&ids).map_err(InternalError::from)?;
+        }
+        Ok(cnt)
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Time for clients to read blocks modified since, later writes are still after it.
+    /// Unlike `now` it does not move the clock ahead of the wall time.
+    pub fn current(&self) -> u64 {
+        let ts = self.clock.current();
+        self.clock.observe(ts);
+        ts
+    }
+
+    /// Positions of all blocks either saved or in memory.
+    pub async fn block_positions(&self) -> PaintResult<Vec<BlockPos>> {
+        let mut blks = self.store.list().await?;
+        for shard in self.shards.iter() {
+            blks.extend(shard.blocks.read().keys().copied());
+        }
+        blks.sort_unstable_by_key(|blk| (blk.x, blk.y));
+        blks.dedup();
+        Ok(blks)
+    }
+
+    pub async fn snapshot_block(&self, blk: BlockPos) -> PaintResult<BlockInfo> {
+        self.read_block(blk, |info| Ok(info.clone())).await
+    }
+
+    /// Replace a block, it is logged with a new mtime so that clients reading changes see it.
+    pub async fn restore_block(&self, blk: BlockPos, info: BlockInfo) -> PaintResult<()> {
+        let mut block = Vec::new();
+        info.save(&mut block)?;
+        self.write_block(blk, |cur| {
+            let ts = self.clock.now();
+            self.commit(cur, Op::RestoreBlock { blk, block, ts })
+        })
+        .await?;
+        self.logged().await
+    }
+
+    /// Write all modified blocks back to the block store.
+    pub async fn flush(&self) -> PaintResult<usize> {
+        let _guard = self.write_back.lock().await;
+        let mut dirty: Vec<(BlockPos, BlockInfo)> = Vec::new();
+        for shard in self.shards.iter() {
+            dirty.extend(shard.blocks.read().iter().filter_map(|(blk, entry)| {
+                let mut info = entry.info.write();
+                if !info.is_dirty() {
+                    return None;
+                }
+                info.set_dirty(false);
+                Some((*blk, info.clone()))
+            }));
+        }
+
+        self.save_blocks(dirty).await
+    }
+
+    /// Draw the pixels allowed to `user`, return the number of pixels drawn.
+    pub async fn draw_pixels<I>(&self, user: &Actor, color: RGBA, pixels: I) -> PaintResult<usize>
+    where
+        I: IntoIterator<Item = PixelPos>,
+    {
+        Ok(self.draw_pixels_report(user, color, pixels).await?.drawn)
+    }
+
+    /// Like `draw_pixels`, also telling which blocks rejected pixels and who holds them.
+    pub async fn draw_pixels_report<I>(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        pixels: I,
+    ) -> PaintResult<DrawReport>
+    where
+        I: IntoIterator<Item = PixelPos>,
+    {
+        let mut report = DrawReport::default();
+        self.draw_into(user, color, pixels, &mut report).await?;
+        Ok(report)
+    }
+
+    async fn draw_into<I>(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        pixels: I,
+        report: &mut DrawReport,
+    ) -> PaintResult<()>
     where
         I: IntoIterator<Item = PixelPos>,
     {
         let mut pixels = pixels.into_iter().peekable();
         let mut offsets = Vec::new();
-        let mut success_cnt = 0;
         while let Some(p) = pixels.next() {
             let blk = p.block();
             offsets.push(p.offset());
@@ -159,50 +728,396 @@ impl PaintDB {
                 offsets.push(p.offset());
                 pixels.next();
             }
-            let ok = self
+            let mut offsets = std::mem::take(&mut offsets);
+            report.drawn += self
                 .write_block(blk, |info| {
-                    Ok(info.draw_pixels(user, color, offsets.iter().cloned()))
+                    let now = self.clock.current();
+                    if !info.can_draw(user, now) {
+                        report.reject(blk, info.locked_by(now), offsets.len());
+                        return Ok(0);
+                    }
+                    let claims = self.claims.read();
+                    if claims.claimed(blk) {
+                        let base = PixelPos::from(blk);
+                        offsets.retain(|o| match claims.blocker(user, base + Delta::from(*o)) {
+                            Some(claim) => {
+                                report.reject(blk, &claim.owner, 1);
+                                false
+                            }
+                            None => true,
+                        });
+                    }
+                    drop(claims);
+                    if offsets.is_empty() {
+                        return Ok(0);
+                    }
+                    let cnt = offsets.len();
+                    let ts = self.clock.now();
+                    let op = Op::DrawPixels {
+                        blk,
+                        color,
+                        offsets,
+                        ts,
+                    };
+                    self.commit(info, op).map(|_| cnt)
                 })
                 .await?;
-            if ok {
-                success_cnt += offsets.len();
+        }
+        self.logged().await
+    }
+
+    /// Assert that the pixels of each block come in a single run, so that `draw_into`
+    /// writes every block once, return the number of blocks.
+    #[cfg(test)]
+    pub fn block_runs(pixels: &[PixelPos]) -> usize {
+        let mut seen = HashSet::new();
+        for (i, p) in pixels.iter().enumerate() {
+            if i == 0 || pixels[i - 1].block() != p.block() {
+                assert!(seen.insert(p.block()), "block {:?} in two runs", p.block());
             }
-            offsets.clear();
         }
-        Ok(success_cnt)
+        seen.len()
     }
 
-    pub async fn draw_lines<I>(
+    /// Draw connected line segments from `start` in the style, telling which pixels were rejected.
+    /// A pixel covered by several segments is drawn once.
+    pub async fn draw_lines(
         &self,
-        user: &str,
+        user: &Actor,
         color: RGBA,
-        mut start: PixelPos,
-        deltas: I,
-    ) -> PaintResult<usize>
-    where
-        I: IntoIterator<Item = Delta>,
-    {
-        let mut success_cnt = 0;
-        for d in deltas {
-            success_cnt += self
-                .draw_pixels(user, color, LineIter::new(start, d))
+        start: PixelPos,
+        moves: &[Delta],
+        style: LineStyle,
+    ) -> PaintResult<DrawReport> {
+        let mut report = DrawReport::default();
+        self.draw_into(user, color, stroke(start, moves, style), &mut report)
+            .await?;
+        Ok(report)
+    }
+
+    /// Fill the rectangle, or draw its outline, each block is locked once.
+    pub async fn draw_rect(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        rect: PixelRect,
+        outline: bool,
+    ) -> PaintResult<DrawReport> {
+        let mut report = DrawReport::default();
+        self.draw_into(user, color, rect.pixels(outline), &mut report)
+            .await?;
+        Ok(report)
+    }
+
+    /// Fill the ellipse, or draw its outline, each block is locked once.
+    pub async fn draw_ellipse(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        ellipse: Ellipse,
+        outline: bool,
+    ) -> PaintResult<DrawReport> {
+        let mut report = DrawReport::default();
+        if outline {
+            self.draw_into(user, color, ellipse.outline(), &mut report)
+                .await?;
+        } else {
+            self.draw_into(user, color, ellipse.fill(), &mut report)
                 .await?;
-            start = start + d;
         }
-        success_cnt += self
-            .draw_pixels(user, color, LineIter::new(start, Delta { x: 0, y: 1 }))
-            .await?;
-        Ok(success_cnt)
+        Ok(report)
+    }
+
+    /// Fill the polygon by the rule, then stroke its border if a stroke color is given.
+    /// A pixel on the stroked border is not filled, so that it is drawn once.
+    pub async fn draw_polygon(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        polygon: &Polygon,
+        rule: FillRule,
+        stroke: Option<RGBA>,
+    ) -> PaintResult<DrawReport> {
+        let mut report = DrawReport::default();
+        match stroke {
+            None => {
+                self.draw_into(user, color, polygon.fill(rule), &mut report)
+                    .await?
+            }
+            Some(stroke) => {
+                let border = polygon.border();
+                let skip: HashSet<PixelPos> = border.iter().copied().collect();
+                let inner = polygon.fill(rule).filter(move |p| !skip.contains(p));
+                self.draw_into(user, color, inner, &mut report).await?;
+                self.draw_into(user, stroke, border, &mut report).await?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fill the 4-connected region of pixels similar to the seed with the color.
+    /// The region stops at blocks the user may not draw on and pixels claimed by others;
+    /// it is drawn as a whole, or not at all if it is too large.
+    pub async fn flood_fill(
+        &self,
+        user: &Actor,
+        color: RGBA,
+        seed: PixelPos,
+        tolerance: u8,
+    ) -> PaintResult<DrawReport> {
+        for _ in 0..FILL_RETRIES {
+            let mut report = DrawReport::default();
+            let mut seen = HashMap::new();
+            let region = self
+                .fill_region(user, seed, tolerance, &mut seen, &mut report)
+                .await?;
+            let mut region: Vec<(BlockPos, Vec<Offset>)> = region.into_iter().collect();
+            region.sort_unstable_by_key(|(blk, _)| (blk.x, blk.y));
+            let blks: Vec<BlockPos> = region.iter().map(|(blk, _)| *blk).collect();
+            let done = self
+                .write_blocks(&blks, |blocks| {
+                    let now = self.clock.current();
+                    let changed = blocks.iter().any(|(blk, info)| {
+                        info.mtime() != seen[blk].mtime || !info.can_draw(user, now)
+                    });
+                    if changed {
+                        return Ok(false);
+                    }
+                    let claims = self.claims.read();
+                    let ts = self.clock.now();
+                    let mut ops = Vec::with_capacity(region.len());
+                    for (blk, mut offsets) in region {
+                        let base = PixelPos::from(blk);
+                        offsets.retain(|o| claims.blocker(user, base + Delta::from(*o)).is_none());
+                        if offsets.is_empty() {
+                            continue;
+                        }
+                        report.drawn += offsets.len();
+                        ops.push(Op::DrawPixels {
+                            blk,
+                            color,
+                            offsets,
+                            ts,
+                        });
+                    }
+                    drop(claims);
+                    self.commit_all(&mut self.locks.lock(), blocks, ops)
+                        .map(|_| true)
+                })
+                .await?;
+            if done {
+                self.logged().await?;
+                return Ok(report);
+            }
+        }
+        Err(PaintError::FillConflict)
+    }
+
+    /// Find the region of a flood fill by the blocks as they are now, without holding them.
+    async fn fill_region(
+        &self,
+        user: &Actor,
+        seed: PixelPos,
+        tolerance: u8,
+        seen: &mut HashMap<BlockPos, FillBlock>,
+        report: &mut DrawReport,
+    ) -> PaintResult<HashMap<BlockPos, Vec<Offset>>> {
+        let mut region: HashMap<BlockPos, Vec<Offset>> = HashMap::new();
+        let target = match self.fill_pixel(user, seed, seen).await? {
+            Ok(color) => color,
+            Err(owner) => {
+                report.reject(seed.block(), &owner, 1);
+                return Ok(region);
+            }
+        };
+        let mut visited = HashSet::new();
+        visited.insert(seed);
+        let mut stack = vec![seed];
+        let mut cnt = 0;
+        while let Some(p) = stack.pop() {
+            cnt += 1;
+            if cnt > MAX_FILL_PIXELS {
+                return Err(PaintError::FillTooLarge(MAX_FILL_PIXELS, MAX_FILL_BLOCKS));
+            }
+            region.entry(p.block()).or_default().push(p.offset());
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)].iter() {
+                let n = match (p.x.checked_add(*dx), p.y.checked_add(*dy)) {
+                    (Some(x), Some(y)) => PixelPos { x, y },
+                    _ => continue,
+                };
+                if visited.contains(&n) {
+                    continue;
+                }
+                if let Ok(color) = self.fill_pixel(user, n, seen).await? {
+                    if similar(color, target, tolerance) {
+                        visited.insert(n);
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+        Ok(region)
+    }
+
+    /// Color of a pixel for a flood fill, or who holds it if the user may not draw it.
+    async fn fill_pixel(
+        &self,
+        user: &Actor,
+        p: PixelPos,
+        seen: &mut HashMap<BlockPos, FillBlock>,
+    ) -> PaintResult<Result<[u8; 3], Username>> {
+        let blk = p.block();
+        if !seen.contains_key(&blk) {
+            if seen.len() >= MAX_FILL_BLOCKS {
+                return Err(PaintError::FillTooLarge(MAX_FILL_PIXELS, MAX_FILL_BLOCKS));
+            }
+            let now = self.clock.current();
+            let mut block = self
+                .read_block(blk, move |info| {
+                    Ok(FillBlock {
+                        mtime: info.mtime(),
+                        pixels: if info.can_draw(user, now) {
+                            Ok(info.pixels().clone())
+                        } else {
+                            Err(info.locked_by(now).to_owned())
+                        },
+                        claimed: HashMap::new(),
+                    })
+                })
+                .await?;
+            let claims = self.claims.read();
+            if claims.claimed(blk) {
+                let base = PixelPos::from(blk);
+                for x in 0..BLOCK_SIZE as u8 {
+                    for y in 0..BLOCK_SIZE as u8 {
+                        if let Some(claim) = claims.blocker(user, base + Delta::from((x, y))) {
+                            block.claimed.insert((x, y), claim.owner.clone());
+                        }
+                    }
+                }
+            }
+            seen.insert(blk, block);
+        }
+        let block = &seen[&blk];
+        if let Some(owner) = block.claimed.get(&p.offset()) {
+            return Ok(Err(owner.clone()));
+        }
+        match &block.pixels {
+            Ok(pixels) => Ok(Ok(pixels.pixel(p.offset()))),
+            Err(owner) => Ok(Err(owner.clone())),
+        }
+    }
+
+    /// Apply the operations in order as a single logged record, all blocks involved are
+    /// held meanwhile. If any pixel is rejected nothing is drawn, the report tells why.
+    pub async fn draw_batch(&self, user: &Actor, batch: Vec<BatchOp>) -> PaintResult<DrawReport> {
+        let mut blks = Vec::new();
+        for op in batch.iter() {
+            match op {
+                BatchOp::Pixels { pixels, .. } => blks.extend(pixels.iter().map(|p| p.block())),
+                BatchOp::Block { blk, .. } => blks.push(*blk),
+            }
+        }
+        let mut report = DrawReport::default();
+        self.write_blocks(&blks, |blocks| {
+            let now = self.clock.current();
+            // each operation gets its own mtime, so that replaying skips none of
+            // those after an earlier one on the same block
+            let mut ops = Vec::new();
+            for op in batch {
+                match op {
+                    BatchOp::Pixels { color, pixels } => {
+                        let mut pixels = pixels.into_iter().peekable();
+                        while let Some(p) = pixels.next() {
+                            let blk = p.block();
+                            let mut offsets = vec![p.offset()];
+                            while let Some(p) = pixels.next_if(|p| p.block() == blk) {
+                                offsets.push(p.offset());
+                            }
+                            ops.push(Op::DrawPixels {
+                                blk,
+                                color,
+                                offsets,
+                                ts: self.clock.now(),
+                            });
+                        }
+                    }
+                    BatchOp::Block { blk, image } => ops.push(Op::DrawBlock {
+                        blk,
+                        image,
+                        ts: self.clock.now(),
+                    }),
+                }
+            }
+
+            let claims = self.claims.read();
+            for op in ops.iter() {
+                let blk = op.block().expect("drawing without a block");
+                let i = blocks
+                    .binary_search_by_key(&(blk.x, blk.y), |(b, _)| (b.x, b.y))
+                    .expect("drawing on a block not locked");
+                let info = &blocks[i].1;
+                let cnt = match op {
+                    Op::DrawPixels { offsets, .. } => offsets.len(),
+                    _ => BLOCK_SIZE * BLOCK_SIZE,
+                };
+                if !info.can_draw(user, now) {
+                    report.reject(blk, info.locked_by(now), cnt);
+                    continue;
+                }
+                match op {
+                    Op::DrawPixels { offsets, .. } => {
+                        let base = PixelPos::from(blk);
+                        for o in offsets.iter() {
+                            if let Some(claim) = claims.blocker(user, base + Delta::from(*o)) {
+                                report.reject(blk, &claim.owner, 1);
+                            }
+                        }
+                    }
+                    _ => {
+                        if let Some(claim) = claims.block_blocker(user, blk) {
+                            report.reject(blk, &claim.owner, cnt);
+                        }
+                    }
+                }
+                report.drawn += cnt;
+            }
+            drop(claims);
+            if !report.rejected.is_empty() {
+                report.drawn = 0;
+                return Ok(());
+            }
+            self.commit_all(&mut self.locks.lock(), blocks, ops)
+        })
+        .await?;
+        self.logged().await?;
+        Ok(report)
     }
 
     pub async fn set_block(
         &self,
-        user: &str,
+        user: &Actor,
         blk: BlockPos,
         image: &RGBABlock,
     ) -> PaintResult<bool> {
-        self.write_block(blk, |info| Ok(info.draw_block(user, image)))
-            .await
+        let done = self
+            .write_block(blk, |info| {
+                if !info.can_draw(user, self.clock.current())
+                    || !self.claims.read().can_draw_block(user, blk)
+                {
+                    return Ok(false);
+                }
+                let ts = self.clock.now();
+                let op = Op::DrawBlock {
+                    blk,
+                    image: image.clone(),
+                    ts,
+                };
+                self.commit(info, op).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
     }
 
     pub async fn get_block<W: Write + Send>(
@@ -215,16 +1130,1278 @@ impl PaintDB {
             .await
     }
 
+    /// End of a lease starting now, capped by the maximum lease.
+    fn lease_expire(&self, lease: u64) -> u64 {
+        let max_lease = self.config.max_lease.as_millis() as u64;
+        self.clock.current().saturating_add(lease.min(max_lease))
+    }
+
+    #[allow(dead_code)]
     pub async fn set_lock(&self, user: Username, blk: BlockPos) -> PaintResult<bool> {
-        self.write_block(blk, |info| Ok(info.set_owner(user))).await
+        let now = self.clock.current();
+        let expire = self.lease_expire(u64::MAX);
+        let done = self
+            .write_block(blk, |info| {
+                if !info.accessable(&user, now) {
+                    return Ok(false);
+                }
+                let op = Op::SetLease {
+                    blk,
+                    owner: user,
+                    expire,
+                };
+                self.commit(info, op).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
+    }
+
+    /// Lock all the blocks for `lease` milliseconds, or none of them if any is locked by others.
+    /// Blocks already locked by the user get the new lease.
+    /// Return positions of the blocks locked by others.
+    pub async fn lock_blocks(
+        &self,
+        user: &str,
+        role: UserRole,
+        blks: &[BlockPos],
+        lease: u64,
+    ) -> PaintResult<Vec<BlockPos>> {
+        let now = self.clock.current();
+        let expire = self.lease_expire(lease);
+        // fail fast without materializing blocks
+        let mut conflicts = Vec::new();
+        for blk in blks.iter() {
+            if !self
+                .read_block(*blk, |info| Ok(info.accessable(user, now)))
+                .await?
+            {
+                conflicts.push(*blk);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Ok(conflicts);
+        }
+
+        let conflicts = self
+            .write_blocks(blks, |blocks| {
+                let conflicts: Vec<BlockPos> = blocks
+                    .iter()
+                    .filter(|(_, info)| !info.accessable(user, now))
+                    .map(|(blk, _)| *blk)
+                    .collect();
+                if !conflicts.is_empty() {
+                    return Ok(conflicts);
+                }
+
+                let mut index = self.locks.lock();
+                let locked = index.count(user, now);
+                let new = blocks
+                    .iter()
+                    .filter(|(_, info)| info.locked_by(now) != user)
+                    .count();
+                let quota = self.config.quota(role).locks;
+                if locked + new > quota {
+                    return Err(PaintError::QuotaExceeded(quota.saturating_sub(locked)));
+                }
+                let ops = blocks
+                    .iter()
+                    .map(|(blk, _)| Op::SetLease {
+                        blk: *blk,
+                        owner: user.to_owned(),
+                        expire,
+                    })
+                    .collect();
+                self.commit_all(&mut index, blocks, ops)?;
+                Ok(conflicts)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(conflicts)
+    }
+
+    pub fn lock_quota(&self, user: &str, role: UserRole) -> Quota {
+        let quota = self.config.quota(role).locks;
+        let locked = self.locks.lock().count(user, self.clock.current());
+        Quota {
+            quota,
+            locked,
+            available: quota.saturating_sub(locked),
+        }
+    }
+
+    /// Extend the lease of a block locked by the user to `lease` milliseconds from now,
+    /// return whether it was locked by the user.
+    pub async fn renew_lock(&self, user: &str, blk: BlockPos, lease: u64) -> PaintResult<bool> {
+        let now = self.clock.current();
+        let expire = self.lease_expire(lease);
+        if self
+            .read_block(blk, |info| Ok(info.locked_by(now) != user))
+            .await?
+        {
+            return Ok(false);
+        }
+        let done = self
+            .write_block(blk, |info| {
+                if info.locked_by(now) != user {
+                    return Ok(false);
+                }
+                let op = Op::SetLease {
+                    blk,
+                    owner: user.to_owned(),
+                    expire,
+                };
+                self.commit(info, op).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
     }
 
-    pub async fn get_lock(&self, blk: BlockPos) -> PaintResult<Username> {
-        self.read_block(blk, |info| Ok(info.get_owner())).await
+    pub async fn get_lock(&self, blk: BlockPos) -> PaintResult<Lock> {
+        let now = self.clock.current();
+        let max_lease = self.config.max_lease.as_millis() as u64;
+        self.read_block(blk, |info| {
+            let owner = info.locked_by(now).to_owned();
+            if owner.is_empty() {
+                return Ok(Lock {
+                    owner,
+                    lease: 0,
+                    collaborators: Vec::new(),
+                });
+            }
+            Ok(Lock {
+                owner,
+                lease: (info.expire() - now).min(max_lease),
+                collaborators: info.collaborators().to_vec(),
+            })
+        })
+        .await
     }
 
+    /// Unlock a block locked by the user, return whether it was.
     pub async fn del_lock(&self, user: &str, blk: BlockPos) -> PaintResult<bool> {
-        self.write_block(blk, |info| Ok(info.reset_owner(user)))
+        let now = self.clock.current();
+        // only a locked block can be unlocked, so a read is enough to skip the others
+        if self
+            .read_block(blk, |info| Ok(info.locked_by(now) != user))
+            .await?
+        {
+            return Ok(false);
+        }
+        let done = self
+            .write_block(blk, |info| {
+                if info.locked_by(now) != user {
+                    return Ok(false);
+                }
+                self.commit(info, Op::ResetOwner { blk }).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
+    }
+
+    /// Unlock a block whoever holds it, return whether it was locked.
+    pub async fn force_unlock(&self, blk: BlockPos) -> PaintResult<bool> {
+        let now = self.clock.current();
+        if self
+            .read_block(blk, |info| Ok(info.locked_by(now).is_empty()))
+            .await?
+        {
+            return Ok(false);
+        }
+        let done = self
+            .write_block(blk, |info| {
+                if info.locked_by(now).is_empty() {
+                    return Ok(false);
+                }
+                self.commit(info, Op::ResetOwner { blk }).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
+    }
+
+    /// Allow `user` to draw on a block locked by `owner`, return whether it is granted.
+    pub async fn grant(&self, owner: &str, blk: BlockPos, user: &str) -> PaintResult<bool> {
+        let now = self.clock.current();
+        if self
+            .read_block(blk, |info| Ok(info.locked_by(now) != owner))
+            .await?
+        {
+            return Ok(false);
+        }
+        let done = self
+            .write_block(blk, |info| {
+                if info.locked_by(now) != owner || !info.can_grant(user) {
+                    return Ok(false);
+                }
+                let op = Op::Grant {
+                    blk,
+                    user: user.to_owned(),
+                };
+                self.commit(info, op).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
+    }
+
+    /// Take back the right to draw from `user`, return whether it is revoked.
+    pub async fn revoke(&self, owner: &str, blk: BlockPos, user: &str) -> PaintResult<bool> {
+        let now = self.clock.current();
+        let granted = |info: &BlockInfo| {
+            info.locked_by(now) == owner && info.collaborators().iter().any(|c| c == user)
+        };
+        if !self.read_block(blk, |info| Ok(granted(info))).await? {
+            return Ok(false);
+        }
+        let done = self
+            .write_block(blk, |info| {
+                if !granted(info) {
+                    return Ok(false);
+                }
+                let op = Op::Revoke {
+                    blk,
+                    user: user.to_owned(),
+                };
+                self.commit(info, op).map(|_| true)
+            })
+            .await?;
+        self.logged().await?;
+        Ok(done)
+    }
+
+    /// Hand the locks of `from` on the blocks over to `to` with the role, keeping their leases.
+    /// Blocks no longer locked by `from` are skipped, return the blocks handed over.
+    pub async fn transfer_locks(
+        &self,
+        from: &str,
+        to: &str,
+        role: UserRole,
+        blks: &[BlockPos],
+    ) -> PaintResult<Vec<BlockPos>> {
+        let now = self.clock.current();
+        let mut owned = Vec::new();
+        for blk in blks.iter() {
+            if self
+                .read_block(*blk, |info| Ok(info.locked_by(now) == from))
+                .await?
+            {
+                owned.push(*blk);
+            }
+        }
+        if owned.is_empty() || from == to {
+            return Ok(Vec::new());
+        }
+
+        let moved = self
+            .write_blocks(&owned, |blocks| {
+                let moved: Vec<(BlockPos, u64)> = blocks
+                    .iter()
+                    .filter(|(_, info)| info.locked_by(now) == from)
+                    .map(|(blk, info)| (*blk, info.expire()))
+                    .collect();
+                let mut index = self.locks.lock();
+                let locked = index.count(to, now);
+                let quota = self.config.quota(role).locks;
+                if locked + moved.len() > quota {
+                    return Err(PaintError::QuotaExceeded(quota.saturating_sub(locked)));
+                }
+                let ops = moved
+                    .iter()
+                    .map(|(blk, expire)| Op::SetLease {
+                        blk: *blk,
+                        owner: to.to_owned(),
+                        expire: *expire,
+                    })
+                    .collect();
+                self.commit_all(&mut index, blocks, ops)?;
+                Ok(moved.into_iter().map(|(blk, _)| blk).collect())
+            })
+            .await?;
+        self.logged().await?;
+        Ok(moved)
+    }
+
+    /// Claim the pixels of the shape for `owner` with the role,
+    /// none of them may be claimed by others nor in blocks `owner` may not draw on.
+    pub async fn claim(&self, owner: &str, role: UserRole, shape: Shape) -> PaintResult<Claim> {
+        shape.validate()?;
+        let claimant = Actor::new(owner.to_owned());
+        let now = self.clock.current();
+        for blk in shape.blocks() {
+            if !self
+                .read_block(blk, |info| Ok(info.can_draw(&claimant, now)))
+                .await?
+            {
+                return Err(PaintError::Locked);
+            }
+        }
+        let claim = self.add_claim(owner, role, shape)?;
+        self.logged().await?;
+        Ok(claim)
+    }
+
+    fn add_claim(&self, owner: &str, role: UserRole, shape: Shape) -> PaintResult<Claim> {
+        // rasterize before taking the lock
+        let area = shape.area();
+        let mut claims = self.claims.write();
+        if !claims.conflicts(owner, &shape).is_empty() {
+            return Err(PaintError::AlreadyClaimed);
+        }
+        let usage = claims.usage(owner);
+        if usage.claims >= MAX_CLAIMS {
+            return Err(PaintError::TooManyClaims(MAX_CLAIMS));
+        }
+        let quota = self.config.quota(role).pixels;
+        if usage.pixels + area > quota {
+            return Err(PaintError::ClaimQuotaExceeded(
+                quota.saturating_sub(usage.pixels),
+            ));
+        }
+        let claim = Claim {
+            id: claims.next_id(),
+            owner: owner.to_owned(),
+            shape,
+        };
+        self.commit_claim(
+            &mut claims,
+            Op::Claim {
+                claim: claim.clone(),
+            },
+        )?;
+        Ok(claim)
+    }
+
+    /// Release a claim of `owner`.
+    pub async fn unclaim(&self, owner: &str, id: u64) -> PaintResult<()> {
+        {
+            let mut claims = self.claims.write();
+            match claims.get(id) {
+                None => return Err(PaintError::ClaimNotFound),
+                Some(claim) if claim.owner != owner => return Err(PaintError::NotClaimOwner),
+                Some(_) => self.commit_claim(&mut claims, Op::Unclaim { id })?,
+            }
+        }
+        self.logged().await
+    }
+
+    /// Drop a claim whoever owns it.
+    pub async fn force_unclaim(&self, id: u64) -> PaintResult<()> {
+        {
+            let mut claims = self.claims.write();
+            match claims.get(id) {
+                None => return Err(PaintError::ClaimNotFound),
+                Some(_) => self.commit_claim(&mut claims, Op::Unclaim { id })?,
+            }
+        }
+        self.logged().await
+    }
+
+    /// Release every lock and claim of `owner`, once it is gone.
+    pub async fn release_all(&self, owner: &str) -> PaintResult<()> {
+        let blks = self.locks.lock().owned(owner);
+        for blk in blks {
+            self.write_block(blk, |info| {
+                if info.get_owner() != owner {
+                    return Ok(());
+                }
+                self.commit(info, Op::ResetOwner { blk })
+            })
+            .await?;
+        }
+        {
+            let mut claims = self.claims.write();
+            for id in claims.owned(owner) {
+                self.commit_claim(&mut claims, Op::Unclaim { id })?;
+            }
+        }
+        self.logged().await
+    }
+
+    /// Claims covering any of the blocks.
+    pub fn claims_in(&self, blks: &[BlockPos]) -> Vec<Claim> {
+        self.claims
+            .read()
+            .in_blocks(blks.iter().copied())
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Release expired locks, return the number of released locks.
+    pub async fn sweep_locks(&self) -> PaintResult<usize> {
+        let now = self.clock.current();
+        let found = self.locks.lock().expired(now);
+
+        let mut released = 0;
+        for blk in found {
+            let ok = self
+                .write_block(blk, |info| {
+                    if info.get_owner().is_empty() {
+                        Ok(false)
+                    } else if info.expire() <= now {
+                        self.commit(info, Op::ResetOwner { blk }).map(|_| true)
+                    } else {
+                        Ok(false)
+                    }
+                })
+                .await?;
+            if ok {
+                released += 1;
+            }
+        }
+        self.logged().await?;
+        Ok(released)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::config::RoleQuota;
+    use crate::paint::store::{FileStore, MemStore};
+    use crate::paint::timestamp::{FakeClock, SystemClock};
+    use hex::FromHex;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn actor(name: &str) -> Actor {
+        Actor::new(name.to_owned())
+    }
+
+    /// A database in memory, with its clock at `now`.
+    fn memory_db(now: u64, config: PaintConfig) -> (PaintDB, Arc<FakeClock>) {
+        let clock = Arc::new(FakeClock::new(now));
+        let db = PaintDB::new(Box::new(MemStore::new()), clock.clone(), None, config);
+        (db, clock)
+    }
+
+    /// A database whose blocks and log live under `dir`.
+    fn logged_db(dir: &Path, clock: Arc<dyn Clock>) -> PaintResult<PaintDB> {
+        let store = FileStore::open(dir.join("blocks")).map_err(InternalError::from)?;
+        let wal = Wal::open(dir.join("wal")).map_err(InternalError::from)?;
+        Ok(PaintDB::new(
+            Box::new(store),
+            clock,
+            Some(wal),
+            PaintConfig::default(),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_mtime() -> PaintResult<()> {
+        let (db, clock) = memory_db(100, PaintConfig::default());
+        let blk = BlockPos { x: 1, y: -1 };
+        let red = RGBA::from_hex("FF0000FF")?;
+
+        assert_eq!(db.get_block(blk, Vec::new(), 0).await?, 0);
+        let p: PixelPos = blk.into();
+        assert_eq!(db.draw_pixels(&actor("luffbee"), red, vec![p]).await?, 1);
+        assert_eq!(db.get_block(blk, Vec::new(), 0).await?, 100);
+
+        clock.set(250);
+        assert_eq!(db.draw_pixels(&actor("luffbee"), red, vec![p]).await?, 1);
+        let mut png = Vec::new();
+        assert_eq!(db.get_block(blk, &mut png, 100).await?, 250);
+        assert!(!png.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_recover() -> PaintResult<()> {
+        let dir = std::env::temp_dir().join(format!("canvast-recover-{}", std::process::id()));
+        let open = || logged_db(&dir, Arc::new(FakeClock::new(100)));
+        let blk = BlockPos { x: 0, y: 0 };
+        let color = RGBA::from_hex("FF000080")?;
+        let mut png = Vec::new();
+
+        let db = open()?;
+        assert_eq!(db.recover().await?, 0);
+        assert!(db.set_lock("luffbee".to_owned(), blk).await?);
+        assert_eq!(
+            db.draw_pixels(&actor("luffbee"), color, vec![blk.into()])
+                .await?,
+            1
+        );
+        db.get_block(blk, &mut png, 0).await?;
+        let shape = Shape::Rect {
+            x: 0,
+            y: 0,
+            w: 20,
+            h: 1,
+        };
+        assert_eq!(db.claim("luffbee", UserRole::User, shape).await?.id, 0);
+        drop(db); // crash without flushing
+
+        let db = open()?;
+        assert_eq!(db.recover().await?, 3);
+        assert_eq!(db.get_lock(blk).await?.owner, "luffbee");
+        assert_eq!(db.lock_quota("luffbee", UserRole::User).locked, 1);
+        assert_eq!(db.claims_in(&[BlockPos { x: 1, y: 0 }]).len(), 1);
+        let mut recovered = Vec::new();
+        assert_eq!(db.get_block(blk, &mut recovered, 0).await?, 100);
+        assert_eq!(png, recovered);
+        drop(db);
+
+        // the log has been folded into the block store, nothing is applied twice
+        let db = open()?;
+        assert_eq!(db.recover().await?, 0);
+        let mut reloaded = Vec::new();
+        db.get_block(blk, &mut reloaded, 0).await?;
+        assert_eq!(png, reloaded);
+        // the lock and claim indexes come from the checkpoint
+        assert_eq!(db.lock_quota("luffbee", UserRole::User).locked, 1);
+        assert_eq!(db.claims_in(&[blk]).len(), 1);
+
+        std::fs::remove_dir_all(&dir).map_err(InternalError::from)?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_read() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        for x in 0..16 {
+            let blk = BlockPos { x, y: 0 };
+            assert_eq!(db.get_block(blk, Vec::new(), 0).await?, 0);
+            assert_eq!(db.get_lock(blk).await?.owner, "");
+        }
+        assert_eq!(db.cache_stats().resident, 0);
+
+        let blk = BlockPos { x: 3, y: 0 };
+        assert!(db.set_lock("luffbee".to_owned(), blk).await?);
+        assert_eq!(db.cache_stats().resident, 1);
+        assert_eq!(db.block_positions().await?, vec![blk]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_lock_blocks() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let rect = |x0, x1| -> Vec<BlockPos> { (x0..x1).map(|x| BlockPos { x, y: 0 }).collect() };
+
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &rect(0, 3), u64::MAX)
+            .await?
+            .is_empty());
+        // locking own blocks again is fine
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &rect(2, 4), u64::MAX)
+            .await?
+            .is_empty());
+        assert_eq!(
+            db.lock_blocks("sam", UserRole::User, &rect(3, 6), u64::MAX)
+                .await?,
+            vec![BlockPos { x: 3, y: 0 }]
+        );
+        // nothing changed for the failed request
+        assert_eq!(db.get_lock(BlockPos { x: 4, y: 0 }).await?.owner, "");
+
+        assert!(!db.del_lock("sam", BlockPos { x: 3, y: 0 }).await?);
+        assert!(db.del_lock("luffbee", BlockPos { x: 3, y: 0 }).await?);
+        assert!(!db.del_lock("luffbee", BlockPos { x: 3, y: 0 }).await?);
+        assert!(db
+            .lock_blocks("sam", UserRole::User, &rect(3, 6), u64::MAX)
+            .await?
+            .is_empty());
+        assert_eq!(db.get_lock(BlockPos { x: 5, y: 0 }).await?.owner, "sam");
+        assert_eq!(db.get_lock(BlockPos { x: 2, y: 0 }).await?.owner, "luffbee");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_lease() -> PaintResult<()> {
+        let config = PaintConfig {
+            max_lease: Duration::from_millis(1000),
+            ..PaintConfig::default()
+        };
+        let (db, clock) = memory_db(100, config);
+        let blks = [BlockPos { x: 0, y: 0 }, BlockPos { x: 1, y: 0 }];
+        let red = RGBA::from_hex("FF0000FF")?;
+
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &blks, 50)
+            .await?
+            .is_empty());
+        let lock = db.get_lock(blks[0]).await?;
+        assert_eq!((lock.owner.as_str(), lock.lease), ("luffbee", 50));
+        // capped by the maximum lease
+        assert!(db.renew_lock("luffbee", blks[1], 5000).await?);
+        assert!(!db.renew_lock("sam", blks[1], 5000).await?);
+        assert_eq!(db.get_lock(blks[1]).await?.lease, 1000);
+
+        clock.set(150);
+        assert_eq!(db.get_lock(blks[0]).await?.owner, "");
+        assert!(!db.renew_lock("luffbee", blks[0], 50).await?);
+        assert_eq!(
+            db.draw_pixels(&actor("sam"), red, vec![blks[0].into()])
+                .await?,
+            1
+        );
+        assert_eq!(
+            db.draw_pixels(&actor("sam"), red, vec![blks[1].into()])
+                .await?,
+            0
+        );
+
+        // expired locks are released by the sweeper
+        assert_eq!(db.sweep_locks().await?, 1);
+        assert_eq!(db.sweep_locks().await?, 0);
+        clock.set(2000);
+        assert_eq!(db.sweep_locks().await?, 1);
+        db.write_block(blks[1], |info| {
+            assert_eq!(info.get_owner(), "");
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_lock_quota() -> PaintResult<()> {
+        let config = PaintConfig {
+            user_quota: RoleQuota {
+                locks: 3,
+                pixels: 0,
+            },
+            moderator_quota: RoleQuota {
+                locks: 6,
+                pixels: 0,
+            },
+            ..PaintConfig::default()
+        };
+        let (db, _) = memory_db(100, config);
+        let rect = |x0, x1| -> Vec<BlockPos> { (x0..x1).map(|x| BlockPos { x, y: 0 }).collect() };
+
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &rect(0, 2), 1000)
+            .await?
+            .is_empty());
+        match db
+            .lock_blocks("luffbee", UserRole::User, &rect(1, 4), 1000)
             .await
+        {
+            Err(PaintError::QuotaExceeded(1)) => (),
+            _ => panic!("quota not checked"),
+        }
+        assert_eq!(db.get_lock(BlockPos { x: 2, y: 0 }).await?.owner, "");
+        // blocks already locked by the user do not count twice
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &rect(1, 3), 1000)
+            .await?
+            .is_empty());
+        assert_eq!(db.lock_quota("luffbee", UserRole::User).available, 0);
+        assert!(db
+            .lock_blocks("sam", UserRole::User, &rect(3, 6), 1000)
+            .await?
+            .is_empty());
+
+        assert!(db.del_lock("luffbee", BlockPos { x: 0, y: 0 }).await?);
+        let quota = db.lock_quota("luffbee", UserRole::User);
+        assert_eq!((quota.quota, quota.locked, quota.available), (3, 2, 1));
+
+        // the quota depends on the role
+        assert!(db
+            .lock_blocks("tom", UserRole::Moderator, &rect(6, 11), 1000)
+            .await?
+            .is_empty());
+        assert_eq!(db.lock_quota("tom", UserRole::Moderator).available, 1);
+        assert_eq!(db.lock_quota("tom", UserRole::User).available, 0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_collaborators() -> PaintResult<()> {
+        let (db, clock) = memory_db(100, PaintConfig::default());
+        let blk = BlockPos { x: 0, y: 0 };
+        let red = RGBA::from_hex("FF0000FF")?;
+        let pixel = || vec![PixelPos { x: 1, y: 1 }];
+
+        assert!(!db.grant("luffbee", blk, "sam").await?);
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+        assert!(!db.grant("tom", blk, "sam").await?);
+        assert!(db.grant("luffbee", blk, "sam").await?);
+        assert!(!db.grant("luffbee", blk, "sam").await?);
+        assert_eq!(
+            db.get_lock(blk).await?.collaborators,
+            vec!["sam".to_owned()]
+        );
+
+        assert_eq!(db.draw_pixels(&actor("sam"), red, pixel()).await?, 1);
+        assert_eq!(db.draw_pixels(&actor("tom"), red, pixel()).await?, 0);
+        // collaborators draw, but do not own the lock
+        assert!(!db.del_lock("sam", blk).await?);
+        assert!(!db
+            .lock_blocks("sam", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+
+        assert!(!db.revoke("tom", blk, "sam").await?);
+        assert!(db.revoke("luffbee", blk, "sam").await?);
+        assert_eq!(db.draw_pixels(&actor("sam"), red, pixel()).await?, 0);
+
+        // a new owner starts with no collaborators
+        assert!(db.grant("luffbee", blk, "sam").await?);
+        clock.set(2000);
+        assert!(db
+            .lock_blocks("tom", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+        assert!(db.get_lock(blk).await?.collaborators.is_empty());
+        assert_eq!(db.draw_pixels(&actor("sam"), red, pixel()).await?, 0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_moderation() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let blk = BlockPos { x: 0, y: 0 };
+        let red = RGBA::from_hex("FF0000FF")?;
+        let pixel = || vec![PixelPos { x: 1, y: 1 }];
+        let admin = Actor {
+            overrides: true,
+            ..actor("admin")
+        };
+
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+        let claim = db
+            .claim(
+                "luffbee",
+                UserRole::User,
+                Shape::Rect {
+                    x: 300,
+                    y: 0,
+                    w: 4,
+                    h: 4,
+                },
+            )
+            .await?;
+        assert_eq!(db.draw_pixels(&actor("tom"), red, pixel()).await?, 0);
+        assert_eq!(db.draw_pixels(&admin, red, pixel()).await?, 1);
+        let claimed = vec![PixelPos { x: 301, y: 1 }];
+        assert_eq!(db.draw_pixels(&admin, red, claimed).await?, 1);
+
+        assert!(db.force_unlock(blk).await?);
+        assert!(!db.force_unlock(blk).await?);
+        assert_eq!(db.draw_pixels(&actor("tom"), red, pixel()).await?, 1);
+        db.force_unclaim(claim.id).await?;
+        assert!(db.force_unclaim(claim.id).await.is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_draw_report() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let red = RGBA::from_hex("FF0000FF")?;
+        let locked = BlockPos { x: 0, y: 0 };
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[locked], 1000)
+            .await?
+            .is_empty());
+        db.claim(
+            "sam",
+            UserRole::User,
+            Shape::Rect {
+                x: 16,
+                y: 0,
+                w: 2,
+                h: 16,
+            },
+        )
+        .await?;
+
+        // from the locked block over the claimed pixels into a free block
+        let report = db
+            .draw_lines(
+                &actor("tom"),
+                red,
+                PixelPos { x: 10, y: 3 },
+                &[Delta { x: 9, y: 0 }],
+                LineStyle::default(),
+            )
+            .await?;
+        assert_eq!(report.drawn, 2);
+        let claimed = BlockPos { x: 1, y: 0 };
+        let rejected: Vec<_> = report
+            .rejected
+            .iter()
+            .map(|r| (r.blk, r.owner.as_str(), r.pixels))
+            .collect();
+        assert_eq!(rejected, vec![(locked, "luffbee", 6), (claimed, "sam", 2)]);
+
+        let report = db
+            .draw_pixels_report(&actor("sam"), red, vec![PixelPos { x: 16, y: 3 }])
+            .await?;
+        assert_eq!((report.drawn, report.rejected.len()), (1, 0));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_batch() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let red = RGBA::from_hex("FF0000FF")?;
+        let (locked, free) = (BlockPos { x: 0, y: 0 }, BlockPos { x: 1, y: 0 });
+        assert!(RGBABlock::from_hex("FF0000FF").is_err());
+        let batch = || -> PaintResult<Vec<BatchOp>> {
+            Ok(vec![
+                BatchOp::Pixels {
+                    color: red,
+                    pixels: vec![PixelPos { x: 20, y: 0 }, PixelPos { x: 21, y: 0 }],
+                },
+                BatchOp::Block {
+                    blk: locked,
+                    image: RGBABlock::from_hex("FF0000FF".repeat(BLOCK_SIZE * BLOCK_SIZE))?,
+                },
+            ])
+        };
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[locked], 1000)
+            .await?
+            .is_empty());
+
+        let report = db.draw_batch(&actor("tom"), batch()?).await?;
+        assert_eq!(report.drawn, 0);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].blk, locked);
+        assert_eq!(report.rejected[0].owner, "luffbee");
+        // the free block is left alone as well
+        assert_eq!(db.get_block(free, Vec::new(), 0).await?, 0);
+
+        let report = db.draw_batch(&actor("luffbee"), batch()?).await?;
+        assert_eq!(report.drawn, 2 + BLOCK_SIZE * BLOCK_SIZE);
+        assert!(report.rejected.is_empty());
+        assert_eq!(db.get_block(free, Vec::new(), 0).await?, 100);
+        assert_eq!(db.get_block(locked, Vec::new(), 0).await?, 100);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_batch_recover() -> PaintResult<()> {
+        let dir = std::env::temp_dir().join(format!("canvast-batch-{}", std::process::id()));
+        let open = || logged_db(&dir, Arc::new(SystemClock::new()));
+        let blk = BlockPos { x: 0, y: 0 };
+        let batch = vec![
+            BatchOp::Block {
+                blk,
+                image: RGBABlock::from_hex("FF0000FF".repeat(BLOCK_SIZE * BLOCK_SIZE))?,
+            },
+            BatchOp::Pixels {
+                color: RGBA::from_hex("00FF00FF")?,
+                pixels: vec![PixelPos { x: 1, y: 1 }],
+            },
+        ];
+
+        let db = open()?;
+        assert_eq!(db.recover().await?, 0);
+        let report = db.draw_batch(&actor("luffbee"), batch).await?;
+        assert_eq!(report.drawn, BLOCK_SIZE * BLOCK_SIZE + 1);
+        let mut png = Vec::new();
+        db.get_block(blk, &mut png, 0).await?;
+        drop(db); // crash without flushing
+
+        // both operations on the block are replayed
+        let db = open()?;
+        assert_eq!(db.recover().await?, 2);
+        let mut recovered = Vec::new();
+        db.get_block(blk, &mut recovered, 0).await?;
+        assert_eq!(png, recovered);
+
+        std::fs::remove_dir_all(&dir).map_err(InternalError::from)?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_flood_fill() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let black = RGBA::from_hex("000000FF")?;
+        let red = RGBA::from_hex("FF0000FF")?;
+        let (tom, seed) = (actor("tom"), PixelPos { x: 5, y: 5 });
+        let frame = PixelRect {
+            x: 0,
+            y: 0,
+            w: 20,
+            h: 20,
+        };
+        db.draw_rect(&tom, black, frame, true).await?;
+
+        assert_eq!(db.flood_fill(&tom, red, seed, 0).await?.drawn, 18 * 18);
+        let reddish = RGBA::from_hex("FE0000FF")?;
+        db.draw_pixels(&tom, reddish, vec![PixelPos { x: 3, y: 3 }])
+            .await?;
+        assert_eq!(db.flood_fill(&tom, red, seed, 0).await?.drawn, 18 * 18 - 1);
+        assert_eq!(db.flood_fill(&tom, red, seed, 1).await?.drawn, 18 * 18);
+
+        // pixels 16 to 18 of the locked block are left out
+        let locked = BlockPos { x: 1, y: 1 };
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[locked], 1000)
+            .await?
+            .is_empty());
+        assert_eq!(db.flood_fill(&tom, red, seed, 0).await?.drawn, 18 * 18 - 9);
+        let report = db
+            .flood_fill(&tom, red, PixelPos { x: 17, y: 17 }, 0)
+            .await?;
+        assert_eq!(report.drawn, 0);
+        assert_eq!(report.rejected[0].owner, "luffbee");
+
+        // the blank canvas is endless
+        match db.flood_fill(&tom, red, PixelPos { x: -5, y: 0 }, 0).await {
+            Err(PaintError::FillTooLarge(..)) => (),
+            _ => panic!("filled the blank canvas"),
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_draw_rect() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let red = RGBA::from_hex("FF0000FF")?;
+        let rect = PixelRect {
+            x: -4,
+            y: 2,
+            w: 24,
+            h: 10,
+        };
+        let locked = BlockPos { x: -1, y: 0 };
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[locked], 1000)
+            .await?
+            .is_empty());
+
+        // pixels on the locked block are rejected, the rest are drawn
+        let report = db.draw_rect(&actor("tom"), red, rect, false).await?;
+        assert_eq!(report.drawn, 20 * 10);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(
+            (report.rejected[0].blk, report.rejected[0].pixels),
+            (locked, 4 * 10)
+        );
+        assert_eq!(db.get_block(locked, Vec::new(), 0).await?, 0);
+
+        let report = db.draw_rect(&actor("luffbee"), red, rect, true).await?;
+        assert_eq!(report.drawn, 2 * 24 + 2 * 8);
+        assert!(report.rejected.is_empty());
+        assert_eq!(db.get_block(locked, Vec::new(), 0).await?, 100);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_polygon() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let black = RGBA::from_hex("000000FF")?;
+        let red = RGBA::from_hex("FF0000FF")?;
+        let tom = actor("tom");
+        let moves = [
+            Delta { x: 20, y: 0 },
+            Delta { x: 0, y: 20 },
+            Delta { x: -20, y: 0 },
+        ];
+        let square = Polygon::new(PixelPos { x: 0, y: 0 }, &moves)?;
+
+        let report = db
+            .draw_polygon(&tom, red, &square, FillRule::EvenOdd, None)
+            .await?;
+        assert_eq!(report.drawn, 20 * 20);
+        // the border covers the fill on two sides and sticks out on the others
+        let report = db
+            .draw_polygon(&tom, red, &square, FillRule::NonZero, Some(black))
+            .await?;
+        assert_eq!(report.drawn, 21 * 21);
+        let inner = db
+            .flood_fill(&tom, black, PixelPos { x: 5, y: 5 }, 0)
+            .await?;
+        assert_eq!(inner.drawn, 19 * 19);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_team_locks() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let blk = BlockPos { x: 0, y: 0 };
+        let red = RGBA::from_hex("FF0000FF")?;
+        let pixel = || vec![PixelPos { x: 1, y: 1 }];
+        let member = Actor {
+            teams: vec!["artists".to_owned()],
+            ..actor("sam")
+        };
+
+        assert!(db
+            .lock_blocks("team:artists", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+        assert_eq!(db.get_lock(blk).await?.owner, "team:artists");
+        assert_eq!(db.draw_pixels(&member, red, pixel()).await?, 1);
+        assert_eq!(db.draw_pixels(&actor("artists"), red, pixel()).await?, 0);
+        assert_eq!(db.draw_pixels(&actor("tom"), red, pixel()).await?, 0);
+        // members draw, but the lock belongs to the team
+        assert!(!db.del_lock("sam", blk).await?);
+        assert!(db.del_lock("team:artists", blk).await?);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_transfer_locks() -> PaintResult<()> {
+        let config = PaintConfig {
+            user_quota: RoleQuota {
+                locks: 2,
+                pixels: 0,
+            },
+            ..PaintConfig::default()
+        };
+        let (db, _) = memory_db(100, config);
+        let blks: Vec<BlockPos> = (0..3).map(|x| BlockPos { x, y: 0 }).collect();
+
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &blks[..2], 1000)
+            .await?
+            .is_empty());
+        assert!(db
+            .lock_blocks("tom", UserRole::User, &blks[2..], 1000)
+            .await?
+            .is_empty());
+        assert!(db.grant("luffbee", blks[0], "tom").await?);
+        assert!(
+            db.lock_blocks("sam", UserRole::User, &blks[2..], 1000)
+                .await?
+                .len()
+                == 1
+        );
+        // the quota of the recipient is checked
+        assert!(db
+            .lock_blocks("sam", UserRole::User, &[BlockPos { x: 9, y: 0 }], 1000)
+            .await?
+            .is_empty());
+        match db
+            .transfer_locks("luffbee", "sam", UserRole::User, &blks)
+            .await
+        {
+            Err(PaintError::QuotaExceeded(1)) => (),
+            _ => panic!("quota not checked"),
+        }
+        assert!(db.del_lock("sam", BlockPos { x: 9, y: 0 }).await?);
+
+        assert_eq!(
+            db.transfer_locks("luffbee", "sam", UserRole::User, &blks)
+                .await?,
+            &blks[..2]
+        );
+        let lock = db.get_lock(blks[0]).await?;
+        assert_eq!((lock.owner.as_str(), lock.lease), ("sam", 1000));
+        assert!(lock.collaborators.is_empty());
+        assert_eq!(db.get_lock(blks[2]).await?.owner, "tom");
+        assert_eq!(db.lock_quota("luffbee", UserRole::User).locked, 0);
+        assert!(db
+            .transfer_locks("luffbee", "sam", UserRole::User, &blks)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_claim_limit() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let dot = |x| Shape::Rect {
+            x,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        for x in 0..MAX_CLAIMS as i64 {
+            db.claim("luffbee", UserRole::User, dot(x)).await?;
+        }
+        match db.claim("luffbee", UserRole::User, dot(-1)).await {
+            Err(PaintError::TooManyClaims(MAX_CLAIMS)) => (),
+            _ => panic!("claim limit not checked"),
+        }
+        db.claim("sam", UserRole::User, dot(-1)).await?;
+        db.unclaim("luffbee", 0).await?;
+        db.claim("luffbee", UserRole::User, dot(-2)).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_claims() -> PaintResult<()> {
+        let config = PaintConfig {
+            user_quota: RoleQuota {
+                locks: 0,
+                pixels: 100,
+            },
+            ..PaintConfig::default()
+        };
+        let (db, _) = memory_db(100, config);
+        let red = RGBA::from_hex("FF0000FF")?;
+        let row = |x0, x1| -> Vec<PixelPos> { (x0..x1).map(|x| PixelPos { x, y: 0 }).collect() };
+        let rect = |x, w| Shape::Rect { x, y: 0, w, h: 1 };
+
+        // both claim a part of the same block
+        let claim = db.claim("luffbee", UserRole::User, rect(0, 8)).await?;
+        db.claim("team:artists", UserRole::User, rect(8, 8)).await?;
+        match db.claim("sam", UserRole::User, rect(4, 8)).await {
+            Err(PaintError::AlreadyClaimed) => (),
+            _ => panic!("claimed pixels of others"),
+        }
+        match db.claim("luffbee", UserRole::User, rect(100, 93)).await {
+            Err(PaintError::ClaimQuotaExceeded(92)) => (),
+            _ => panic!("quota not checked"),
+        }
+
+        let member = Actor {
+            teams: vec!["artists".to_owned()],
+            ..actor("sam")
+        };
+        assert_eq!(
+            db.draw_pixels(&actor("luffbee"), red, row(0, 20)).await?,
+            12
+        );
+        assert_eq!(db.draw_pixels(&member, red, row(0, 20)).await?, 12);
+        assert_eq!(db.draw_pixels(&actor("tom"), red, row(0, 20)).await?, 4);
+        let image = RGBABlock::new();
+        assert!(
+            !db.set_block(&actor("luffbee"), BlockPos { x: 0, y: 0 }, &image)
+                .await?
+        );
+        assert!(
+            db.set_block(&actor("tom"), BlockPos { x: 1, y: 0 }, &image)
+                .await?
+        );
+
+        match db.unclaim("sam", claim.id).await {
+            Err(PaintError::NotClaimOwner) => (),
+            _ => panic!("released a claim of others"),
+        }
+        db.unclaim("luffbee", claim.id).await?;
+        assert!(db.unclaim("luffbee", claim.id).await.is_err());
+        assert_eq!(db.draw_pixels(&actor("tom"), red, row(0, 16)).await?, 8);
+        assert_eq!(db.claims_in(&[BlockPos { x: 0, y: 0 }]).len(), 1);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_claim_locked() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let blk = BlockPos { x: 0, y: 0 };
+        let rect = |x| Shape::Rect {
+            x,
+            y: 0,
+            w: 8,
+            h: 8,
+        };
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &[blk], 1000)
+            .await?
+            .is_empty());
+        match db.claim("sam", UserRole::User, rect(4)).await {
+            Err(PaintError::Locked) => (),
+            _ => panic!("claimed pixels in a block locked by others"),
+        }
+        assert!(db.claims_in(&[blk]).is_empty());
+        db.claim("luffbee", UserRole::User, rect(4)).await?;
+        db.claim("sam", UserRole::User, rect(16)).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_release_all() -> PaintResult<()> {
+        let (db, _) = memory_db(100, PaintConfig::default());
+        let blks = [BlockPos { x: 0, y: 0 }, BlockPos { x: 1, y: 0 }];
+        let owner = "team:artists";
+        assert!(db
+            .lock_blocks(owner, UserRole::User, &blks, 1000)
+            .await?
+            .is_empty());
+        let dot = |x| Shape::Rect {
+            x,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        db.claim(owner, UserRole::User, dot(40)).await?;
+        db.claim("sam", UserRole::User, dot(41)).await?;
+
+        db.release_all(owner).await?;
+        assert_eq!(db.lock_quota(owner, UserRole::User).locked, 0);
+        assert!(db.get_lock(blks[1]).await?.owner.is_empty());
+        assert_eq!(db.claims_in(&[BlockPos { x: 2, y: 0 }]).len(), 1);
+        let red = RGBA::from_hex("FF0000FF")?;
+        let row: Vec<PixelPos> = (0..41).map(|x| PixelPos { x, y: 0 }).collect();
+        assert_eq!(db.draw_pixels(&actor("tom"), red, row).await?, 41);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_eviction() -> PaintResult<()> {
+        let config = PaintConfig {
+            cache_blocks: 2,
+            ..PaintConfig::default()
+        };
+        let (db, _) = memory_db(100, config);
+        let blks = [
+            BlockPos { x: 0, y: 0 },
+            BlockPos { x: 1, y: 0 },
+            BlockPos { x: 2, y: 0 },
+        ];
+        let red = RGBA::from_hex("FF0000FF")?;
+
+        assert_eq!(
+            db.draw_pixels(&actor("luffbee"), red, vec![blks[0].into()])
+                .await?,
+            1
+        );
+        assert_eq!(
+            db.draw_pixels(&actor("luffbee"), red, vec![blks[1].into()])
+                .await?,
+            1
+        );
+        // touch the first block, so the second one is the least recently used
+        db.get_block(blks[0], Vec::new(), 0).await?;
+        assert_eq!(
+            db.draw_pixels(&actor("luffbee"), red, vec![blks[2].into()])
+                .await?,
+            1
+        );
+        let stats = db.cache_stats();
+        assert_eq!(stats.resident, 2);
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+        // a dirty block is written back before it is evicted
+        assert_eq!(stats.write_backs, 1);
+
+        assert_eq!(db.get_block(blks[1], Vec::new(), 0).await?, 100);
+        let stats = db.cache_stats();
+        assert_eq!(
+            (stats.resident, stats.evictions, stats.write_backs),
+            (2, 2, 2)
+        );
+        assert_eq!(db.get_lock(blks[0]).await?.owner, "");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_block_limit() -> PaintResult<()> {
+        let config = PaintConfig {
+            cache_blocks: 4,
+            ..PaintConfig::default()
+        };
+        let (db, _) = memory_db(100, config);
+        let blks: Vec<_> = (0..3).map(|x| BlockPos { x, y: 0 }).collect();
+
+        // more blocks than half of the cache are refused before any is loaded
+        match db.lock_blocks("luffbee", UserRole::User, &blks, 1000).await {
+            Err(PaintError::TooManyBlocks(2)) => (),
+            _ => panic!("expect too many blocks"),
+        }
+        assert_eq!(db.cache_stats().resident, 0);
+        assert!(db
+            .lock_blocks("luffbee", UserRole::User, &blks[..2], 1000)
+            .await?
+            .is_empty());
+        assert_eq!(db.lock_quota("luffbee", UserRole::User).locked, 2);
+        Ok(())
     }
 }
@@ -0,0 +1,223 @@
+use serde_derive::Deserialize;
+
+use std::cmp::max;
+
+use super::data::{block_range, span_fits, PixelPos, BLOCK_BITS};
+use super::error::{PaintError, PaintResult};
+
+/// Largest radius of an ellipse drawn at once, in pixels.
+const MAX_RADIUS: u32 = 512;
+
+/// Ellipse centered at a pixel with horizontal radius `rx` and vertical radius `ry`,
+/// a circle if they are equal.
+#[derive(Deserialize, Clone, Copy)]
+pub struct Ellipse {
+    pub center: PixelPos,
+    pub rx: u32,
+    pub ry: u32,
+}
+
+impl Ellipse {
+    pub fn validate(&self) -> PaintResult<()> {
+        if self.rx > MAX_RADIUS || self.ry > MAX_RADIUS {
+            return Err(PaintError::InvalidData(format!(
+                "radius must be at most {}",
+                MAX_RADIUS
+            )));
+        }
+        let (cx, cy) = (self.center.x as i128, self.center.y as i128);
+        let (rx, ry) = (self.rx as i128, self.ry as i128);
+        let size = 2 * MAX_RADIUS as i64;
+        if !span_fits(cx - rx, cx + rx, size) || !span_fits(cy - ry, cy + ry, size) {
+            return Err(PaintError::InvalidData("ellipse out of range".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Points of the first quadrant of the outline relative to the center,
+    /// by the midpoint algorithm with all decision variables scaled by 4.
+    fn quadrant(&self) -> Vec<(i64, i64)> {
+        let (rx, ry) = (self.rx as i64, self.ry as i64);
+        if ry == 0 {
+            return (0..=rx).map(|x| (x, 0)).collect();
+        }
+        let (a2, b2) = (rx * rx, ry * ry);
+        let mut points = Vec::new();
+        let (mut x, mut y) = (0, ry);
+        let (mut dx, mut dy) = (0, 2 * a2 * y);
+
+        // the slope is above -1, step x
+        let mut d = 4 * b2 - 4 * a2 * ry + a2;
+        while dx < dy {
+            points.push((x, y));
+            x += 1;
+            dx += 2 * b2;
+            if d < 0 {
+                d += 4 * (dx + b2);
+            } else {
+                y -= 1;
+                dy -= 2 * a2;
+                d += 4 * (dx - dy + b2);
+            }
+        }
+
+        // the slope is below -1, step y
+        let mut d = b2 * (2 * x + 1) * (2 * x + 1) + 4 * a2 * (y - 1) * (y - 1) - 4 * a2 * b2;
+        while y >= 0 {
+            points.push((x, y));
+            y -= 1;
+            dy -= 2 * a2;
+            if d > 0 {
+                d += 4 * (a2 - dy);
+            } else {
+                x += 1;
+                dx += 2 * b2;
+                d += 4 * (dx - dy + a2);
+            }
+        }
+        points
+    }
+
+    /// Pixels of the outline, each given once, block by block.
+    pub fn outline(&self) -> Vec<PixelPos> {
+        let c = self.center;
+        let mut pixels: Vec<PixelPos> = self
+            .quadrant()
+            .into_iter()
+            .flat_map(|(x, y)| {
+                vec![(x, y), (-x, y), (x, -y), (-x, -y)]
+                    .into_iter()
+                    .map(move |(x, y)| PixelPos {
+                        x: c.x + x,
+                        y: c.y + y,
+                    })
+            })
+            .collect();
+        pixels.sort_unstable_by_key(|p| (p.block().x, p.block().y, p.x, p.y));
+        pixels.dedup();
+        pixels
+    }
+
+    /// Pixels of the outline and everything inside it, block by block.
+    pub fn fill(&self) -> impl Iterator<Item = PixelPos> {
+        // half width of each row, by its distance to the center
+        let mut half = vec![0; self.ry as usize + 1];
+        for (x, y) in self.quadrant() {
+            half[y as usize] = max(half[y as usize], x);
+        }
+        let c = self.center;
+        let (rx, ry) = (self.rx as i64, self.ry as i64);
+        let (bx0, bx1) = ((c.x - rx) >> BLOCK_BITS, (c.x + rx) >> BLOCK_BITS);
+        let (by0, by1) = ((c.y - ry) >> BLOCK_BITS, (c.y + ry) >> BLOCK_BITS);
+        (bx0..=bx1)
+            .flat_map(move |bx| (by0..=by1).map(move |by| (bx, by)))
+            .flat_map(move |(bx, by)| {
+                let ys = block_range(by, c.y - ry, c.y + ry);
+                let half = half.clone();
+                ys.flat_map(move |y| {
+                    let w = half[(y - c.y).unsigned_abs() as usize];
+                    let xs = block_range(bx, c.x - w, c.x + w);
+                    xs.map(move |x| PixelPos { x, y })
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::db::PaintDB;
+    use std::collections::HashSet;
+
+    fn ellipse(rx: u32, ry: u32) -> Ellipse {
+        Ellipse {
+            center: PixelPos { x: 7, y: -3 },
+            rx,
+            ry,
+        }
+    }
+
+    fn offsets<I: IntoIterator<Item = PixelPos>>(pixels: I) -> Vec<(i64, i64)> {
+        let mut out: Vec<_> = pixels.into_iter().map(|p| (p.x - 7, p.y + 3)).collect();
+        out.sort_unstable();
+        out
+    }
+
+    #[test]
+    fn test_small() {
+        assert_eq!(offsets(ellipse(0, 0).outline()), vec![(0, 0)]);
+        assert_eq!(offsets(ellipse(0, 0).fill()), vec![(0, 0)]);
+        assert_eq!(
+            offsets(ellipse(1, 1).outline()),
+            vec![(-1, 0), (0, -1), (0, 1), (1, 0)]
+        );
+        assert_eq!(ellipse(1, 1).fill().count(), 5);
+        assert_eq!(ellipse(3, 0).outline().len(), 7);
+        assert_eq!(ellipse(0, 3).fill().count(), 7);
+    }
+
+    #[test]
+    fn test_ellipse() {
+        for (rx, ry) in [(5, 5), (20, 7), (7, 20), (100, 100), (512, 3)].iter() {
+            let e = ellipse(*rx, *ry);
+            assert!(e.validate().is_ok());
+            let outline = e.outline();
+            let fill: Vec<PixelPos> = e.fill().collect();
+            let filled: HashSet<(i64, i64)> = fill.iter().map(|p| (p.x, p.y)).collect();
+            let ring: HashSet<(i64, i64)> = outline.iter().map(|p| (p.x, p.y)).collect();
+            // no pixel twice
+            assert_eq!(ring.len(), outline.len());
+            assert_eq!(filled.len(), fill.len());
+            for (x, y) in ring.iter() {
+                assert!(filled.contains(&(*x, *y)));
+                // symmetric
+                assert!(ring.contains(&(14 - x, *y)) && ring.contains(&(*x, -6 - y)));
+                // 8-connected, no gaps
+                let neighbors = (-1..=1)
+                    .flat_map(|i| (-1..=1).map(move |j| (i, j)))
+                    .filter(|(i, j)| (*i, *j) != (0, 0) && ring.contains(&(x + i, y + j)))
+                    .count();
+                assert!(neighbors >= 2);
+            }
+            // every filled pixel next to the outside is on the outline
+            for (x, y) in filled.iter() {
+                let inner = [(1, 0), (-1, 0), (0, 1), (0, -1)]
+                    .iter()
+                    .all(|(i, j)| filled.contains(&(x + i, y + j)));
+                assert!(inner || ring.contains(&(*x, *y)));
+            }
+            PaintDB::block_runs(&outline);
+            PaintDB::block_runs(&fill);
+        }
+        assert!(ellipse(513, 1).validate().is_err());
+        let far = Ellipse {
+            center: PixelPos { x: i64::MIN, y: 0 },
+            rx: 1,
+            ry: 1,
+        };
+        assert!(far.validate().is_err());
+
+        // the edge of the canvas
+        for center in [
+            PixelPos {
+                x: 0,
+                y: i64::MAX - 5,
+            },
+            PixelPos {
+                x: i64::MAX - 5,
+                y: i64::MIN + 5,
+            },
+        ] {
+            let edge = Ellipse {
+                center,
+                rx: 5,
+                ry: 5,
+            };
+            assert!(edge.validate().is_ok());
+            let fill: Vec<PixelPos> = edge.fill().collect();
+            assert_eq!(fill.len(), ellipse(5, 5).fill().count());
+            assert!(fill.iter().any(|p| p.y == center.y + 5));
+            assert_eq!(edge.outline().len(), ellipse(5, 5).outline().len());
+        }
+    }
+}
@@ -16,6 +16,26 @@ pub enum PaintError {
     PNGDecodeError(#[from] png::DecodingError),
     #[error("invalid data: {0}")]
     InvalidData(String),
+    #[error("lock quota exceeded, {0} blocks available")]
+    QuotaExceeded(usize),
+    #[error("claim quota exceeded, {0} pixels available")]
+    ClaimQuotaExceeded(usize),
+    #[error("claim limit reached, at most {0} claims")]
+    TooManyClaims(usize),
+    #[error("pixels already claimed by others")]
+    AlreadyClaimed,
+    #[error("block locked by others")]
+    Locked,
+    #[error("claim not found")]
+    ClaimNotFound,
+    #[error("not the owner of the claim")]
+    NotClaimOwner,
+    #[error("fill region exceeds {0} pixels or {1} blocks")]
+    FillTooLarge(usize, usize),
+    #[error("blocks changed during the fill, try again")]
+    FillConflict,
+    #[error("request spans more than {0} blocks")]
+    TooManyBlocks(usize),
 }
 
 impl ResponseError for PaintError {
@@ -23,9 +43,13 @@ impl ResponseError for PaintError {
         use PaintError::*;
         match self {
             Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            InvalidPNGName | InvalidPNG(_) | PNGDecodeError(_) | InvalidData(_) => {
-                StatusCode::UNPROCESSABLE_ENTITY
+            InvalidPNGName | InvalidPNG(_) | PNGDecodeError(_) | InvalidData(_)
+            | FillTooLarge(..) | TooManyBlocks(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            QuotaExceeded(_) | ClaimQuotaExceeded(_) | TooManyClaims(_) | NotClaimOwner => {
+                StatusCode::FORBIDDEN
             }
+            AlreadyClaimed | Locked | FillConflict => StatusCode::CONFLICT,
+            ClaimNotFound => StatusCode::NOT_FOUND,
         }
     }
 }
@@ -40,6 +64,18 @@ pub enum InternalError {
     BlockLoadSendError(#[from] SendError<()>),
     #[error("block loading retry limit exceeded")]
     BlockLoadLimitExceeded,
+    #[error("io error")]
+    IOError(#[from] std::io::Error),
+    #[error("corrupted block file")]
+    CorruptedBlock,
+    #[error("block store canceled")]
+    BlockStoreCanceled,
+    #[error("corrupted operation log")]
+    CorruptedLog,
+    #[error("operation log write failed")]
+    LogWriteFailed,
+    #[error("operation log encoding error")]
+    LogEncodeError(#[from] bincode::Error),
 }
 
 impl ResponseError for InternalError {}
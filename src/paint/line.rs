@@ -1,3 +1,7 @@
+use serde_derive::Deserialize;
+
+use std::cmp::{max, min};
+
 use super::data::{Delta, PixelPos};
 
 pub struct LineIter {
@@ -49,6 +53,223 @@ impl Iterator for LineIter {
     }
 }
 
+/// Pixels of connected segments from `start`, a joint is given once by the segment it starts.
+pub fn polyline(start: PixelPos, moves: &[Delta]) -> impl Iterator<Item = PixelPos> + '_ {
+    let end = moves.iter().fold(start, |p, d| p + *d);
+    moves
+        .iter()
+        .scan(start, |p, d| {
+            let line = LineIter::new(*p, *d);
+            *p = *p + *d;
+            Some(line)
+        })
+        .flatten()
+        .chain(std::iter::once(end))
+}
+
+/// Shape of a wide line where it ends or turns.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnd {
+    /// A disk as wide as the line.
+    #[default]
+    Round,
+    /// The segments go on for half the width.
+    Square,
+}
+
+/// How lines are drawn, a line one pixel wide is drawn by Bresenham.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct LineStyle {
+    pub width: u16,
+    /// The ends of the lines.
+    pub cap: LineEnd,
+    /// Where one segment meets the next.
+    pub join: LineEnd,
+}
+
+impl Default for LineStyle {
+    fn default() -> Self {
+        Self {
+            width: 1,
+            cap: LineEnd::Round,
+            join: LineEnd::Round,
+        }
+    }
+}
+
+/// Pixels of connected segments from `start` in the style, each given once, block by block.
+pub fn stroke(start: PixelPos, moves: &[Delta], style: LineStyle) -> Vec<PixelPos> {
+    let mut pixels: Vec<PixelPos> = if style.width <= 1 {
+        polyline(start, moves).collect()
+    } else {
+        // pixels off the canvas are dropped
+        wide_polyline(moves, style)
+            .into_iter()
+            .filter_map(
+                |(x, y)| match (start.x.checked_add(x), start.y.checked_add(y)) {
+                    (Some(x), Some(y)) => Some(PixelPos { x, y }),
+                    _ => None,
+                },
+            )
+            .collect()
+    };
+    pixels.sort_unstable_by_key(|p| (p.block().x, p.block().y, p.x, p.y));
+    pixels.dedup();
+    pixels
+}
+
+/// Part of a wide line in doubled coordinates from the start, where pixel centers are even.
+enum Piece {
+    /// Pixels within half the width of the segment from `a` along `d`,
+    /// which goes on for half the width before and after it by `ext`.
+    Segment {
+        a: (i64, i64),
+        d: (i64, i64),
+        ext: (bool, bool),
+    },
+    Disk((i64, i64)),
+    Square((i64, i64)),
+}
+
+impl Piece {
+    /// Whether the pixel at `p` is in the piece of a line `w` wide.
+    fn contains(&self, p: (i64, i64), w: i64) -> bool {
+        let w2 = (w * w) as i128;
+        match *self {
+            Piece::Segment { a, d, ext } => {
+                let (vx, vy) = ((p.0 - a.0) as i128, (p.1 - a.1) as i128);
+                let (dx, dy) = (d.0 as i128, d.1 as i128);
+                let len2 = dx * dx + dy * dy;
+                let (across, along) = (vx * dy - vy * dx, vx * dx + vy * dy);
+                across * across <= w2 * len2
+                    && (along >= 0 || ext.0 && along * along <= w2 * len2)
+                    && (along <= len2 || ext.1 && (along - len2) * (along - len2) <= w2 * len2)
+            }
+            Piece::Disk(c) => {
+                let (vx, vy) = ((p.0 - c.0) as i128, (p.1 - c.1) as i128);
+                vx * vx + vy * vy <= w2
+            }
+            Piece::Square(c) => (p.0 - c.0).abs() <= w && (p.1 - c.1).abs() <= w,
+        }
+    }
+
+    /// Range of y the piece may cover.
+    fn rows(&self, w: i64) -> (i64, i64) {
+        match *self {
+            Piece::Segment { a, d, .. } => (min(a.1, a.1 + d.1) - w, max(a.1, a.1 + d.1) + w),
+            Piece::Disk(c) | Piece::Square(c) => (c.1 - w, c.1 + w),
+        }
+    }
+
+    /// Rough range of x the piece covers on the row at `y`, pixels near it are to be checked.
+    fn row(&self, y: i64, w: i64) -> Option<(f64, f64)> {
+        let w = w as f64;
+        match *self {
+            Piece::Segment { a, d, ext } => {
+                let (vy, dx, dy) = ((y - a.1) as f64, d.0 as f64, d.1 as f64);
+                let len2 = dx * dx + dy * dy;
+                let wl = w * len2.sqrt();
+                let (mut lo, mut hi) = (f64::NEG_INFINITY, f64::INFINITY);
+                let mut clip = |l: f64, r: f64| {
+                    lo = lo.max(l.min(r));
+                    hi = hi.min(l.max(r));
+                };
+                // within half the width across the segment
+                if d.1 != 0 {
+                    clip((vy * dx - wl) / dy, (vy * dx + wl) / dy);
+                } else if (vy * dx).abs() > wl + 1.0 {
+                    return None;
+                }
+                // and along it
+                let t0 = if ext.0 { -wl } else { 0.0 };
+                let t1 = len2 + if ext.1 { wl } else { 0.0 };
+                if d.0 != 0 {
+                    clip((t0 - vy * dy) / dx, (t1 - vy * dy) / dx);
+                } else if vy * dy < t0 - 1.0 || vy * dy > t1 + 1.0 {
+                    return None;
+                }
+                Some((a.0 as f64 + lo, a.0 as f64 + hi))
+            }
+            Piece::Disk(c) => {
+                let vy = (y - c.1) as f64;
+                if vy.abs() > w {
+                    return None;
+                }
+                let r = (w * w - vy * vy).sqrt();
+                Some((c.0 as f64 - r, c.0 as f64 + r))
+            }
+            Piece::Square(c) => {
+                if ((y - c.1) as f64).abs() > w {
+                    return None;
+                }
+                Some((c.0 as f64 - w, c.0 as f64 + w))
+            }
+        }
+    }
+}
+
+/// Pixels of a line wider than one pixel as offsets from its start, some more than once.
+/// A line of an even width lies between pixels, half a pixel off its points on both axes.
+fn wide_polyline(moves: &[Delta], style: LineStyle) -> Vec<(i64, i64)> {
+    let w = style.width as i64;
+    let shift = (w + 1) % 2;
+    let mut points = vec![(shift, shift)];
+    for d in moves.iter() {
+        let (x, y) = points[points.len() - 1];
+        let p = (x + 2 * d.x as i64, y + 2 * d.y as i64);
+        if p != (x, y) {
+            points.push(p);
+        }
+    }
+    let n = points.len();
+    let end = |i: usize| {
+        if i == 0 || i == n - 1 {
+            style.cap
+        } else {
+            style.join
+        }
+    };
+
+    let mut pieces = Vec::new();
+    if n == 1 {
+        pieces.push(match style.cap {
+            LineEnd::Round => Piece::Disk(points[0]),
+            LineEnd::Square => Piece::Square(points[0]),
+        });
+    }
+    for (i, pair) in points.windows(2).enumerate() {
+        pieces.push(Piece::Segment {
+            a: pair[0],
+            d: (pair[1].0 - pair[0].0, pair[1].1 - pair[0].1),
+            ext: (end(i) == LineEnd::Square, end(i + 1) == LineEnd::Square),
+        });
+    }
+    for (i, p) in points.iter().enumerate() {
+        if n > 1 && end(i) == LineEnd::Round {
+            pieces.push(Piece::Disk(*p));
+        }
+    }
+
+    let mut pixels = Vec::new();
+    for piece in pieces.iter() {
+        let (y0, y1) = piece.rows(w);
+        for y in y0.div_euclid(2)..=y1.div_euclid(2) {
+            if let Some((x0, x1)) = piece.row(2 * y, w) {
+                let x0 = (x0 / 2.0).floor() as i64 - 1;
+                let x1 = (x1 / 2.0).ceil() as i64 + 1;
+                pixels.extend(
+                    (x0..=x1)
+                        .filter(|x| piece.contains((2 * x, 2 * y), w))
+                        .map(|x| (x, y)),
+                );
+            }
+        }
+    }
+    pixels
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,6 +283,7 @@ mod tests {
         }
     }
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_line() {
         let path2_5 = vec![(0i64, 0i64), (0, 1), (1, 2), (1, 3), (2, 4)];
         for sgn0 in [1i64, -1i64].iter() {
@@ -101,6 +323,106 @@ mod tests {
     }
 
     #[test]
+    fn test_polyline() {
+        let moves = [Delta { x: 2, y: 0 }, Delta { x: 0, y: -2 }];
+        let out: Vec<(i64, i64)> = polyline(PixelPos { x: 1, y: 1 }, &moves)
+            .map(|p| (p.x, p.y))
+            .collect();
+        assert_eq!(out, vec![(1, 1), (2, 1), (3, 1), (3, 0), (3, -1)]);
+        let out: Vec<PixelPos> = polyline(PixelPos { x: 1, y: 1 }, &[]).collect();
+        assert_eq!(out.len(), 1);
+    }
+
+    fn style(width: u16, cap: LineEnd, join: LineEnd) -> LineStyle {
+        LineStyle { width, cap, join }
+    }
+
+    #[test]
+    fn test_stroke() {
+        use LineEnd::*;
+        let origin = PixelPos { x: 0, y: 0 };
+        // going back over the line draws it once
+        let back = [Delta { x: 4, y: 0 }, Delta { x: -4, y: 0 }];
+        assert_eq!(stroke(origin, &back, LineStyle::default()).len(), 5);
+
+        let right = [Delta { x: 10, y: 0 }];
+        let rows = |pixels: &[PixelPos]| {
+            let mut ys: Vec<i64> = pixels.iter().map(|p| p.y).collect();
+            ys.sort_unstable();
+            ys.dedup();
+            ys
+        };
+        let line = stroke(origin, &right, style(3, Square, Round));
+        assert_eq!(rows(&line), vec![-1, 0, 1]);
+        assert_eq!(line.len(), 3 * 13);
+        let line = stroke(origin, &right, style(2, Square, Round));
+        assert_eq!(rows(&line), vec![0, 1]);
+        let line = stroke(origin, &right, style(8, Square, Round));
+        assert_eq!(line.len(), 8 * 18);
+        let line = stroke(origin, &right, style(8, Round, Round));
+        assert!(line.len() < 8 * 18 && line.len() > 8 * 10);
+
+        // a square joint fills the outer corner of a right angle
+        let corner = [Delta { x: 10, y: 0 }, Delta { x: 0, y: 10 }];
+        let square = stroke(origin, &corner, style(5, Square, Square));
+        assert!(square.contains(&PixelPos { x: 12, y: -2 }));
+        assert_eq!(square.len(), 5 * 15 * 2 - 25);
+        let round = stroke(origin, &corner, style(5, Square, Round));
+        assert!(!round.contains(&PixelPos { x: 12, y: -2 }));
+
+        let dot = stroke(origin, &[Delta { x: 0, y: 0 }], style(4, Square, Round));
+        assert_eq!(dot.len(), 16);
+    }
+
+    #[test]
+    fn test_round_stroke() {
+        // round ends cover the pixels within half the width of the path
+        let start = PixelPos { x: -3, y: 5 };
+        let moves = [
+            Delta { x: 17, y: 6 },
+            Delta { x: -5, y: 21 },
+            Delta { x: -20, y: -30 },
+        ];
+        for width in [2u16, 5, 12].iter() {
+            let pixels = stroke(start, &moves, style(*width, LineEnd::Round, LineEnd::Round));
+            let mut points = vec![start];
+            for d in moves.iter() {
+                points.push(points[points.len() - 1] + *d);
+            }
+            let shift = if width % 2 == 0 { 0.5 } else { 0.0 };
+            let r = *width as f64 / 2.0;
+            let dist = |x: f64, y: f64| {
+                points
+                    .windows(2)
+                    .map(|w| {
+                        let (ax, ay) = (w[0].x as f64 + shift, w[0].y as f64 + shift);
+                        let (dx, dy) = ((w[1].x - w[0].x) as f64, (w[1].y - w[0].y) as f64);
+                        let t = (((x - ax) * dx + (y - ay) * dy) / (dx * dx + dy * dy)).max(0.0);
+                        let t = t.min(1.0);
+                        ((x - ax - t * dx).powi(2) + (y - ay - t * dy).powi(2)).sqrt()
+                    })
+                    .fold(f64::INFINITY, f64::min)
+            };
+            let mut cnt = 0;
+            for x in -40..40 {
+                for y in -30..50 {
+                    let d = dist(x as f64, y as f64);
+                    if (d - r).abs() < 1e-9 {
+                        continue;
+                    }
+                    let p = PixelPos { x, y };
+                    assert_eq!(d < r, pixels.contains(&p), "({}, {}) {}", x, y, width);
+                    cnt += (d < r) as usize;
+                }
+            }
+            assert!(cnt > 0 && pixels.len() >= cnt);
+            let mut seen = std::collections::HashSet::new();
+            assert!(pixels.iter().all(|p| seen.insert((p.x, p.y))));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::legacy_numeric_constants)]
     fn test_overflow() {
         use std::i64;
         check_line(
@@ -120,4 +442,20 @@ mod tests {
             [(i64::MIN, i64::MIN), (i64::MAX, i64::MAX)].iter().copied(),
         );
     }
+
+    #[test]
+    fn test_stroke_edge() {
+        // the column past i64::MAX is dropped, not wrapped to the far side
+        let start = PixelPos {
+            x: i64::MAX - 1,
+            y: 0,
+        };
+        let down = [Delta { x: 0, y: 10 }];
+        let square = style(5, LineEnd::Square, LineEnd::Square);
+        let pixels = stroke(start, &down, square);
+        assert!(pixels.iter().all(|p| p.x >= i64::MAX - 3));
+        assert!(pixels.iter().any(|p| p.x == i64::MAX));
+        let origin = PixelPos { x: 0, y: 0 };
+        assert_eq!(pixels.len(), stroke(origin, &down, square).len() / 5 * 4);
+    }
 }
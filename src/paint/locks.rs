@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+use crate::user::Username;
+
+use super::data::{BlockInfo, BlockPos};
+use super::error::InternalError;
+
+/// Owners of all locked blocks, whether in memory or not,
+/// to count the locks of a user and find expired ones without loading blocks.
+#[derive(Default)]
+pub struct LockIndex {
+    blocks: HashMap<BlockPos, (Username, u64)>,
+    users: HashMap<Username, HashSet<BlockPos>>,
+}
+
+impl LockIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the lock of a block, an empty owner means unlocked.
+    pub fn set(&mut self, blk: BlockPos, owner: &str, expire: u64) {
+        if let Some((old, _)) = self.blocks.remove(&blk) {
+            if let Some(blks) = self.users.get_mut(&old) {
+                blks.remove(&blk);
+                if blks.is_empty() {
+                    self.users.remove(&old);
+                }
+            }
+        }
+        if owner.is_empty() {
+            return;
+        }
+        self.blocks.insert(blk, (owner.to_owned(), expire));
+        self.users.entry(owner.to_owned()).or_default().insert(blk);
+    }
+
+    pub fn update(&mut self, blk: BlockPos, info: &BlockInfo) {
+        self.set(blk, info.get_owner(), info.expire());
+    }
+
+    /// Number of blocks locked by the user and not expired.
+    pub fn count(&self, user: &str, now: u64) -> usize {
+        self.users.get(user).map_or(0, |blks| {
+            blks.iter().filter(|blk| self.blocks[blk].1 > now).count()
+        })
+    }
+
+    /// Blocks locked by the owner, expired or not.
+    pub fn owned(&self, owner: &str) -> Vec<BlockPos> {
+        self.users
+            .get(owner)
+            .map_or_else(Vec::new, |blks| blks.iter().copied().collect())
+    }
+
+    /// Blocks whose lock has expired.
+    pub fn expired(&self, now: u64) -> Vec<BlockPos> {
+        self.blocks
+            .iter()
+            .filter(|(_, (_, expire))| *expire <= now)
+            .map(|(blk, _)| *blk)
+            .collect()
+    }
+
+    pub fn save<W: Write>(&self, w: W) -> Result<(), InternalError> {
+        let locks: Vec<(&BlockPos, &(Username, u64))> = self.blocks.iter().collect();
+        bincode::serialize_into(w, &locks)?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(r: R) -> Result<Self, InternalError> {
+        let locks: Vec<(BlockPos, (Username, u64))> = bincode::deserialize_from(r)?;
+        let mut this = Self::new();
+        for (blk, (owner, expire)) in locks {
+            this.set(blk, &owner, expire);
+        }
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_index() -> Result<(), InternalError> {
+        let blk = |x| BlockPos { x, y: 0 };
+        let mut index = LockIndex::new();
+        index.set(blk(0), "luffbee", 100);
+        index.set(blk(1), "luffbee", 200);
+        index.set(blk(2), "sam", 280);
+        assert_eq!(index.count("luffbee", 50), 2);
+        assert_eq!(index.count("luffbee", 150), 1);
+        assert_eq!(index.count("bob", 0), 0);
+
+        index.set(blk(1), "sam", 300);
+        index.set(blk(0), "", 0);
+        assert_eq!(index.count("luffbee", 0), 0);
+        assert_eq!(index.count("sam", 0), 2);
+
+        let mut data = Vec::new();
+        index.save(&mut data)?;
+        let index = LockIndex::load(&data[..])?;
+        assert_eq!(index.count("sam", 250), 2);
+        let mut expired = index.expired(300);
+        expired.sort_unstable_by_key(|blk| blk.x);
+        assert_eq!(expired, vec![blk(1), blk(2)]);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use actix_web::{
     error::Result,
     web,
-    web::{Bytes, Data, Json, Query},
+    web::{Bytes, Data, Json, Path, Query},
     HttpRequest, HttpResponse,
 };
 use hex::FromHex;
@@ -12,23 +12,49 @@ use serde_derive::{Deserialize, Serialize};
 use std::cmp::max;
 use std::io::{Cursor, Seek, Write};
 
-use crate::user::{authenticate, UserDB};
+use crate::user::{
+    authenticate_actor, authorize, Actor, LockRequest, RequestKind, Teamname, UserDB, UserError,
+    UserRole, Username,
+};
 
+mod claims;
+use claims::{Claim, Shape};
+mod config;
+pub use config::PaintConfig;
 mod data;
-pub use data::PixelPos;
 use data::*;
+pub use data::{PixelPos, RGBA};
 mod db;
-pub use db::PaintDB;
+use db::{BatchOp, Quota};
+pub use db::{CacheStats, PaintDB};
+mod ellipse;
+use ellipse::Ellipse;
 mod error;
-use error::InternalError;
-pub use error::{PaintError, PaintResult};
+pub use error::{InternalError, PaintError, PaintResult};
 mod line;
+use line::{stroke, LineStyle};
+mod locks;
+mod polygon;
+use polygon::{FillRule, Polygon};
+mod rect;
+use rect::PixelRect;
+mod snapshot;
+pub use snapshot::{export, import};
+mod store;
+pub use store::{BlockStore, FileStore, MemStore};
 mod timestamp;
-pub use timestamp::now;
+pub use timestamp::{Clock, SystemClock};
+mod wal;
+pub use wal::Wal;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/pixels", web::patch().to(draw_pixels))
         .route("/lines", web::patch().to(draw_lines))
+        .route("/rects", web::patch().to(draw_rect))
+        .route("/ellipses", web::patch().to(draw_ellipse))
+        .route("/fills", web::patch().to(flood_fill))
+        .route("/polygons", web::patch().to(draw_polygon))
+        .route("/batch", web::post().to(draw_batch))
         .service(
             web::resource("/blocks")
                 .route(web::get().to(get_blocks))
@@ -38,12 +64,65 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             web::resource("/locks")
                 .route(web::get().to(get_locks))
                 .route(web::post().to(set_locks))
+                .route(web::put().to(renew_locks))
                 .route(web::delete().to(del_locks)),
+        )
+        .route("/locks/quota", web::get().to(get_quota))
+        .service(
+            web::resource("/locks/collaborators")
+                .route(web::post().to(grant_locks))
+                .route(web::delete().to(revoke_locks)),
+        )
+        .service(
+            web::resource("/claims")
+                .route(web::get().to(get_claims))
+                .route(web::post().to(new_claim)),
+        )
+        .route("/claims/{id}", web::delete().to(del_claim))
+        .route("/locks/transfers", web::post().to(transfer_locks))
+        .route("/locks/requests", web::post().to(request_access))
+        .route("/inbox", web::get().to(get_inbox))
+        .service(
+            web::resource("/inbox/{id}")
+                .route(web::post().to(accept_request))
+                .route(web::delete().to(del_request)),
         );
 }
 
+/// Moderation, mounted under `/admin` of the paint scope.
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/pixels", web::patch().to(force_draw_pixels))
+        .route("/lines", web::patch().to(force_draw_lines))
+        .route("/locks", web::delete().to(force_del_locks))
+        .route("/claims/{id}", web::delete().to(force_del_claim));
+}
+
+pub async fn get_time(pdb: Data<PaintDB>) -> Json<u64> {
+    Json(pdb.current())
+}
+
 #[derive(Serialize)]
-struct SuccessCount(#[serde(rename = "success_pixels")] usize);
+struct DrawResult {
+    success_pixels: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected: Option<Vec<Rejected>>,
+}
+
+impl DrawResult {
+    fn new(report: DrawReport, detail: bool) -> Self {
+        Self {
+            success_pixels: report.drawn,
+            rejected: if detail { Some(report.rejected) } else { None },
+        }
+    }
+}
+
+/// Whether a draw lists the blocks that rejected pixels.
+#[derive(Deserialize)]
+struct Detail {
+    #[serde(default)]
+    detail: bool,
+}
 
 #[derive(Deserialize)]
 struct PixelsBody {
@@ -73,15 +152,38 @@ async fn draw_pixels(
     udb: Data<UserDB>,
     pdb: Data<PaintDB>,
     req: HttpRequest,
+    Query(detail): Query<Detail>,
     body: Json<PixelsBody>,
-) -> Result<Json<SuccessCount>> {
+) -> Result<Json<DrawResult>> {
     let color = RGBA::from_hex(&body.color)?;
     body.validate()?;
-    let user = authenticate(&udb, &req).await?;
+    let user = authenticate_actor(&udb, &req).await?;
+    do_draw_pixels(&pdb, &user, color, &body, detail.detail).await
+}
+
+/// Draw pixels through locks and claims.
+async fn force_draw_pixels(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    body: Json<PixelsBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    body.validate()?;
+    let user = override_actor(&udb, &req).await?;
+    do_draw_pixels(&pdb, &user, color, &body, false).await
+}
+
+async fn do_draw_pixels(
+    pdb: &PaintDB,
+    user: &Actor,
+    color: RGBA,
+    body: &PixelsBody,
+    detail: bool,
+) -> Result<Json<DrawResult>> {
     let offsets = body.offsets.iter().map(|d| body.base + *d);
-    Ok(Json(SuccessCount(
-        pdb.draw_pixels(&user, color, offsets).await?,
-    )))
+    let report = pdb.draw_pixels_report(user, color, offsets).await?;
+    Ok(Json(DrawResult::new(report, detail)))
 }
 
 #[derive(Deserialize)]
@@ -89,12 +191,23 @@ struct LinesBody {
     color: String,
     start: PixelPos,
     moves: Vec<Delta>,
+    #[serde(flatten)]
+    style: LineStyle,
 }
 
 impl LinesBody {
     fn validate(&self) -> PaintResult<()> {
         const MAX_MOVE_ABS: i16 = 2048;
         const MAX_MOVES_SUM: usize = 2048 * 4;
+        const MAX_LINE_WIDTH: u16 = 64;
+        const MAX_LINE_AREA: usize = 1 << 20;
+
+        if self.style.width == 0 || self.style.width > MAX_LINE_WIDTH {
+            return Err(PaintError::InvalidData(format!(
+                "line width must be 1 to {}",
+                MAX_LINE_WIDTH
+            )));
+        }
 
         let mut sum: usize = 0;
         for mv in self.moves.iter() {
@@ -106,23 +219,256 @@ impl LinesBody {
                 return Err(PaintError::InvalidData("line too long".to_owned()));
             }
         }
+        if self.area() > MAX_LINE_AREA {
+            return Err(PaintError::InvalidData("line too wide".to_owned()));
+        }
         Ok(())
     }
+
+    /// Bounds the pixels looked at for the line without drawing it,
+    /// its length by its width, each joint counts as a square.
+    fn area(&self) -> usize {
+        let sum: usize = self
+            .moves
+            .iter()
+            .map(|mv| max(mv.x.unsigned_abs(), mv.y.unsigned_abs()) as usize)
+            .sum();
+        let width = self.style.width as usize;
+        if width > 1 {
+            width * (sum + width * (self.moves.len() + 1))
+        } else {
+            sum + 1
+        }
+    }
 }
 
 async fn draw_lines(
     udb: Data<UserDB>,
     pdb: Data<PaintDB>,
     req: HttpRequest,
+    Query(detail): Query<Detail>,
     body: Json<LinesBody>,
-) -> Result<Json<SuccessCount>> {
+) -> Result<Json<DrawResult>> {
     let color = RGBA::from_hex(&body.color)?;
     body.validate()?;
-    let user = authenticate(&udb, &req).await?;
-    Ok(Json(SuccessCount(
-        pdb.draw_lines(&user, color, body.start, body.moves.iter().copied())
-            .await?,
-    )))
+    let user = authenticate_actor(&udb, &req).await?;
+    do_draw_lines(&pdb, &user, color, &body, detail.detail).await
+}
+
+/// Draw lines through locks and claims.
+async fn force_draw_lines(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    body: Json<LinesBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    body.validate()?;
+    let user = override_actor(&udb, &req).await?;
+    do_draw_lines(&pdb, &user, color, &body, false).await
+}
+
+async fn do_draw_lines(
+    pdb: &PaintDB,
+    user: &Actor,
+    color: RGBA,
+    body: &LinesBody,
+    detail: bool,
+) -> Result<Json<DrawResult>> {
+    let report = pdb
+        .draw_lines(user, color, body.start, &body.moves, body.style)
+        .await?;
+    Ok(Json(DrawResult::new(report, detail)))
+}
+
+#[derive(Deserialize)]
+struct RectBody {
+    color: String,
+    rect: PixelRect,
+    /// Only the one pixel wide border.
+    #[serde(default)]
+    outline: bool,
+}
+
+async fn draw_rect(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(detail): Query<Detail>,
+    body: Json<RectBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    body.rect.validate()?;
+    let user = authenticate_actor(&udb, &req).await?;
+    let report = pdb.draw_rect(&user, color, body.rect, body.outline).await?;
+    Ok(Json(DrawResult::new(report, detail.detail)))
+}
+
+#[derive(Deserialize)]
+struct EllipseBody {
+    color: String,
+    #[serde(flatten)]
+    ellipse: Ellipse,
+    /// Only the one pixel wide outline.
+    #[serde(default)]
+    outline: bool,
+}
+
+async fn draw_ellipse(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(detail): Query<Detail>,
+    body: Json<EllipseBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    body.ellipse.validate()?;
+    let user = authenticate_actor(&udb, &req).await?;
+    let report = pdb
+        .draw_ellipse(&user, color, body.ellipse, body.outline)
+        .await?;
+    Ok(Json(DrawResult::new(report, detail.detail)))
+}
+
+#[derive(Deserialize)]
+struct FillBody {
+    color: String,
+    seed: PixelPos,
+    /// Largest difference of a channel from the seed color still filled.
+    #[serde(default)]
+    tolerance: u8,
+}
+
+async fn flood_fill(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(detail): Query<Detail>,
+    body: Json<FillBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    let user = authenticate_actor(&udb, &req).await?;
+    let report = pdb
+        .flood_fill(&user, color, body.seed, body.tolerance)
+        .await?;
+    Ok(Json(DrawResult::new(report, detail.detail)))
+}
+
+#[derive(Deserialize)]
+struct PolygonBody {
+    color: String,
+    start: PixelPos,
+    moves: Vec<Delta>,
+    #[serde(default)]
+    rule: FillRule,
+    /// Color of the border, not stroked if absent.
+    stroke: Option<String>,
+}
+
+async fn draw_polygon(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(detail): Query<Detail>,
+    body: Json<PolygonBody>,
+) -> Result<Json<DrawResult>> {
+    let color = RGBA::from_hex(&body.color)?;
+    let stroke = match &body.stroke {
+        Some(stroke) => Some(RGBA::from_hex(stroke)?),
+        None => None,
+    };
+    let polygon = Polygon::new(body.start, &body.moves)?;
+    let user = authenticate_actor(&udb, &req).await?;
+    let report = pdb
+        .draw_polygon(&user, color, &polygon, body.rule, stroke)
+        .await?;
+    Ok(Json(DrawResult::new(report, detail.detail)))
+}
+
+/// An operation of a batch, tagged by its kind.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BatchItem {
+    Pixels(PixelsBody),
+    Lines(LinesBody),
+    Block { x: i64, y: i64, image: String },
+}
+
+impl BatchItem {
+    /// Bounds the pixels of the operation, known before it is drawn.
+    fn area(&self) -> usize {
+        match self {
+            BatchItem::Pixels(body) => body.offsets.len(),
+            BatchItem::Lines(body) => body.area(),
+            BatchItem::Block { .. } => BLOCK_SIZE * BLOCK_SIZE,
+        }
+    }
+
+    fn to_op(&self) -> PaintResult<BatchOp> {
+        Ok(match self {
+            BatchItem::Pixels(body) => {
+                body.validate()?;
+                BatchOp::Pixels {
+                    color: RGBA::from_hex(&body.color)?,
+                    pixels: body.offsets.iter().map(|d| body.base + *d).collect(),
+                }
+            }
+            BatchItem::Lines(body) => {
+                body.validate()?;
+                BatchOp::Pixels {
+                    color: RGBA::from_hex(&body.color)?,
+                    pixels: stroke(body.start, &body.moves, body.style),
+                }
+            }
+            BatchItem::Block { x, y, image } => BatchOp::Block {
+                blk: BlockPos { x: *x, y: *y },
+                image: RGBABlock::from_hex(image)?,
+            },
+        })
+    }
+}
+
+/// Draw all operations of the batch in order, or none of them if any pixel is rejected.
+async fn draw_batch(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    items: Json<Vec<BatchItem>>,
+) -> Result<Json<DrawResult>> {
+    const MAX_BATCH_OPS: usize = 64;
+    const MAX_BATCH_PIXELS: usize = 16384;
+
+    if items.len() > MAX_BATCH_OPS {
+        return Err(PaintError::InvalidData("too many operations".to_owned()).into());
+    }
+    let too_many = || PaintError::InvalidData("too many pixels".to_owned());
+    // an operation is only drawn if its bound fits in what is left
+    let mut left = MAX_BATCH_PIXELS;
+    let mut batch = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        if item.area() > left {
+            return Err(too_many().into());
+        }
+        let op = item.to_op()?;
+        let pixels = match &op {
+            BatchOp::Pixels { pixels, .. } => pixels.len(),
+            BatchOp::Block { .. } => BLOCK_SIZE * BLOCK_SIZE,
+        };
+        left = left.checked_sub(pixels).ok_or_else(too_many)?;
+        batch.push(op);
+    }
+    let user = authenticate_actor(&udb, &req).await?;
+    let report = pdb.draw_batch(&user, batch).await?;
+    Ok(Json(DrawResult::new(report, true)))
+}
+
+/// An admin drawing through the locks and claims of others.
+async fn override_actor(udb: &Data<UserDB>, req: &HttpRequest) -> Result<Actor> {
+    let user = authorize(udb, req, UserRole::Admin).await?;
+    Ok(Actor {
+        overrides: true,
+        ..udb.actor(user.name).await?
+    })
 }
 
 #[derive(Deserialize)]
@@ -145,7 +491,7 @@ async fn get_blocks(pdb: Data<PaintDB>, Query(rect): Query<RectTs>) -> Result<Ht
         for j in 0..rect.h {
             let mut data = Vec::<u8>::new();
             let ts = pdb
-                .get_block(base + (i as u8, j as u8), Cursor::new(&mut data), rect.ts)
+                .get_block(base + (i, j), Cursor::new(&mut data), rect.ts)
                 .await?;
             if ts > rect.ts {
                 let name = format!("{}_{}_{}.png", i, j, ts);
@@ -212,7 +558,7 @@ async fn set_blocks(
     let body = Cursor::new(body);
     let mut ziper = zip::ZipArchive::new(body).map_err(InternalError::from)?;
 
-    let user = authenticate(&udb, &req).await?;
+    let user = authenticate_actor(&udb, &req).await?;
 
     let mut fails = Vec::new();
     for i in 0..ziper.len() {
@@ -230,16 +576,430 @@ async fn set_blocks(
     Ok(Json(fails))
 }
 
-async fn get_locks(udb: Data<UserDB>, req: HttpRequest) -> Result<String> {
-    Ok(authenticate(&udb, &req).await?)
+#[derive(Deserialize)]
+struct Rect {
+    x: i64,
+    y: i64,
+    w: u8,
+    h: u8,
+}
+
+impl Rect {
+    fn base(&self) -> BlockPos {
+        BlockPos {
+            x: self.x,
+            y: self.y,
+        }
+    }
+
+    /// Offsets of all blocks in the rectangle, column by column.
+    fn offsets(&self) -> impl Iterator<Item = Offset> {
+        let h = self.h;
+        (0..self.w).flat_map(move |i| (0..h).map(move |j| (i, j)))
+    }
+}
+
+#[derive(Deserialize)]
+struct RectLease {
+    x: i64,
+    y: i64,
+    w: u8,
+    h: u8,
+    /// In milliseconds, the maximum lease if absent.
+    lease: Option<u64>,
+}
+
+impl RectLease {
+    fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            w: self.w,
+            h: self.h,
+        }
+    }
+
+    fn lease(&self) -> u64 {
+        self.lease.unwrap_or(u64::MAX)
+    }
+}
+
+/// The `team` to act on behalf of, instead of the user itself.
+#[derive(Deserialize)]
+struct OnBehalf {
+    team: Option<Teamname>,
+}
+
+/// Authenticate the user, and find the lock owner it acts as.
+async fn lock_owner(udb: &Data<UserDB>, req: &HttpRequest, on: OnBehalf) -> Result<Username> {
+    Ok(quota_owner(udb, req, on).await?.0)
+}
+
+/// Like `lock_owner`, also telling the role whose quotas apply, teams have those of users.
+async fn quota_owner(
+    udb: &Data<UserDB>,
+    req: &HttpRequest,
+    on: OnBehalf,
+) -> Result<(Username, UserRole)> {
+    let user = authorize(udb, req, UserRole::User).await?;
+    let role = match on.team {
+        Some(_) => UserRole::User,
+        None => user.role,
+    };
+    Ok((udb.act_as(user.name, on.team.as_deref()).await?, role))
+}
+
+/// `locks[x][y]` is the lock of the block at offset `(x, y)`.
+async fn get_locks(pdb: Data<PaintDB>, Query(rect): Query<Rect>) -> Result<Json<Vec<Vec<Lock>>>> {
+    let base = rect.base();
+    let mut locks = vec![Vec::with_capacity(rect.h as usize); rect.w as usize];
+    for (i, j) in rect.offsets() {
+        locks[i as usize].push(pdb.get_lock(base + (i, j)).await?);
+    }
+    Ok(Json(locks))
+}
+
+async fn set_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(query): Query<RectLease>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<Delta>>> {
+    let (user, role) = quota_owner(&udb, &req, on).await?;
+    let base = query.rect().base();
+    let blks: Vec<BlockPos> = query.rect().offsets().map(|offset| base + offset).collect();
+    let conflicts = pdb.lock_blocks(&user, role, &blks, query.lease()).await?;
+    Ok(Json(
+        conflicts
+            .into_iter()
+            .map(|blk| Delta {
+                x: (blk.x - base.x) as i16,
+                y: (blk.y - base.y) as i16,
+            })
+            .collect(),
+    ))
+}
+
+async fn renew_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(query): Query<RectLease>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<Delta>>> {
+    let user = lock_owner(&udb, &req, on).await?;
+    let rect = query.rect();
+    let base = rect.base();
+    let mut renewed = Vec::new();
+    for offset in rect.offsets() {
+        if pdb.renew_lock(&user, base + offset, query.lease()).await? {
+            renewed.push(Delta::from(offset));
+        }
+    }
+    Ok(Json(renewed))
+}
+
+async fn del_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(rect): Query<Rect>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<Delta>>> {
+    let user = lock_owner(&udb, &req, on).await?;
+    let base = rect.base();
+    let mut unlocked = Vec::new();
+    for offset in rect.offsets() {
+        if pdb.del_lock(&user, base + offset).await? {
+            unlocked.push(Delta::from(offset));
+        }
+    }
+    Ok(Json(unlocked))
+}
+
+/// Unlock the blocks of the rectangle whoever holds them.
+async fn force_del_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(rect): Query<Rect>,
+) -> Result<Json<Vec<Delta>>> {
+    authorize(&udb, &req, UserRole::Admin).await?;
+    let base = rect.base();
+    let mut unlocked = Vec::new();
+    for offset in rect.offsets() {
+        if pdb.force_unlock(base + offset).await? {
+            unlocked.push(Delta::from(offset));
+        }
+    }
+    Ok(Json(unlocked))
+}
+
+#[derive(Deserialize)]
+struct RectUser {
+    x: i64,
+    y: i64,
+    w: u8,
+    h: u8,
+    user: Username,
 }
 
-async fn set_locks(_udb: Data<UserDB>, _req: HttpRequest) -> Result<&'static str> {
-    Ok("set locks")
+impl RectUser {
+    fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            w: self.w,
+            h: self.h,
+        }
+    }
 }
 
-async fn del_locks(_udb: Data<UserDB>, _req: HttpRequest) -> Result<&'static str> {
-    Ok("delete locks")
+async fn grant_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(query): Query<RectUser>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<Delta>>> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    if !udb.exists(&query.user).await? {
+        return Err(UserError::UserNotFound.into());
+    }
+    let rect = query.rect();
+    let base = rect.base();
+    let mut granted = Vec::new();
+    for offset in rect.offsets() {
+        if pdb.grant(&owner, base + offset, &query.user).await? {
+            granted.push(Delta::from(offset));
+        }
+    }
+    Ok(Json(granted))
+}
+
+async fn revoke_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(query): Query<RectUser>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<Delta>>> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    let rect = query.rect();
+    let base = rect.base();
+    let mut revoked = Vec::new();
+    for offset in rect.offsets() {
+        if pdb.revoke(&owner, base + offset, &query.user).await? {
+            revoked.push(Delta::from(offset));
+        }
+    }
+    Ok(Json(revoked))
+}
+
+async fn get_quota(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Quota>> {
+    let (user, role) = quota_owner(&udb, &req, on).await?;
+    Ok(Json(pdb.lock_quota(&user, role)))
+}
+
+/// Ask `user` to take over the locks of the current user in the rectangle.
+async fn transfer_locks(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(query): Query<RectUser>,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<LockRequest>> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    if !udb.exists(&query.user).await? {
+        return Err(UserError::UserNotFound.into());
+    }
+    if query.user == owner {
+        return Err(
+            PaintError::InvalidData("transfer locks to the owner itself".to_owned()).into(),
+        );
+    }
+    let rect = query.rect();
+    let base = rect.base();
+    let mut locked = false;
+    for offset in rect.offsets() {
+        if pdb.get_lock(base + offset).await?.owner == owner {
+            locked = true;
+            break;
+        }
+    }
+    if !locked {
+        return Err(PaintError::InvalidData("no block locked in the rectangle".to_owned()).into());
+    }
+    let request = LockRequest {
+        id: 0,
+        kind: RequestKind::Transfer,
+        sender: owner,
+        recipient: query.user,
+        x: rect.x,
+        y: rect.y,
+        w: rect.w,
+        h: rect.h,
+    };
+    Ok(Json(udb.send_request(request).await?))
+}
+
+/// Ask owners of the blocks in the rectangle the current user can not draw on
+/// for the right to draw, one request per owner.
+async fn request_access(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(rect): Query<Rect>,
+) -> Result<Json<Vec<LockRequest>>> {
+    let user = authenticate_actor(&udb, &req).await?;
+    let base = rect.base();
+    let mut owners: Vec<Username> = Vec::new();
+    for offset in rect.offsets() {
+        let lock = pdb.get_lock(base + offset).await?;
+        if !lock.owner.is_empty()
+            && !user.owns(&lock.owner)
+            && !lock.collaborators.contains(&user.name)
+            && !owners.contains(&lock.owner)
+        {
+            owners.push(lock.owner);
+        }
+    }
+    let mut requests = Vec::new();
+    for owner in owners {
+        let request = LockRequest {
+            id: 0,
+            kind: RequestKind::Access,
+            sender: user.name.clone(),
+            recipient: owner,
+            x: rect.x,
+            y: rect.y,
+            w: rect.w,
+            h: rect.h,
+        };
+        requests.push(udb.send_request(request).await?);
+    }
+    Ok(Json(requests))
+}
+
+async fn get_inbox(
+    udb: Data<UserDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+) -> Result<Json<Vec<LockRequest>>> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    Ok(Json(udb.inbox(&owner).await?))
+}
+
+/// Take over the locks of a transfer, or let the sender of an access request draw.
+/// Return offsets of the blocks changed.
+async fn accept_request(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+    id: Path<(i32,)>,
+) -> Result<Json<Vec<Delta>>> {
+    let (owner, role) = quota_owner(&udb, &req, on).await?;
+    let request = udb.get_request(id.0).await?;
+    if request.recipient != owner {
+        return Err(UserError::RequestNotFound.into());
+    }
+    let rect = Rect {
+        x: request.x,
+        y: request.y,
+        w: request.w,
+        h: request.h,
+    };
+    let base = rect.base();
+    let blks: Vec<BlockPos> = rect.offsets().map(|offset| base + offset).collect();
+    let changed = match request.kind {
+        RequestKind::Transfer => {
+            pdb.transfer_locks(&request.sender, &owner, role, &blks)
+                .await?
+        }
+        RequestKind::Access => {
+            let mut granted = Vec::new();
+            for blk in blks {
+                if pdb.grant(&owner, blk, &request.sender).await? {
+                    granted.push(blk);
+                }
+            }
+            granted
+        }
+    };
+    udb.del_request(request.id).await?;
+    Ok(Json(
+        changed
+            .into_iter()
+            .map(|blk| Delta {
+                x: (blk.x - base.x) as i16,
+                y: (blk.y - base.y) as i16,
+            })
+            .collect(),
+    ))
+}
+
+/// Deny a request, or withdraw a request sent.
+async fn del_request(
+    udb: Data<UserDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+    id: Path<(i32,)>,
+) -> Result<HttpResponse> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    let request = udb.get_request(id.0).await?;
+    if request.recipient != owner && request.sender != owner {
+        return Err(UserError::RequestNotFound.into());
+    }
+    udb.del_request(request.id).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Claims covering any block of the rectangle.
+async fn get_claims(pdb: Data<PaintDB>, Query(rect): Query<Rect>) -> Json<Vec<Claim>> {
+    let base = rect.base();
+    let blks: Vec<BlockPos> = rect.offsets().map(|offset| base + offset).collect();
+    Json(pdb.claims_in(&blks))
+}
+
+async fn new_claim(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+    shape: Json<Shape>,
+) -> Result<Json<Claim>> {
+    let (owner, role) = quota_owner(&udb, &req, on).await?;
+    Ok(Json(pdb.claim(&owner, role, shape.into_inner()).await?))
+}
+
+async fn del_claim(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    Query(on): Query<OnBehalf>,
+    id: Path<(u64,)>,
+) -> Result<HttpResponse> {
+    let owner = lock_owner(&udb, &req, on).await?;
+    pdb.unclaim(&owner, id.0).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn force_del_claim(
+    udb: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    id: Path<(u64,)>,
+) -> Result<HttpResponse> {
+    authorize(&udb, &req, UserRole::Admin).await?;
+    pdb.force_unclaim(id.0).await?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[inline]
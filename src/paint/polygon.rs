@@ -0,0 +1,260 @@
+use serde_derive::Deserialize;
+
+use std::sync::Arc;
+
+use super::data::{block_range, span_fits, Delta, PixelPos, BLOCK_BITS};
+use super::error::{PaintError, PaintResult};
+use super::line::LineIter;
+
+/// Longest side of the bounding box of a polygon, in pixels.
+const MAX_POLYGON_SIZE: i64 = 1024;
+/// Most vertices of a polygon, drawn or claimed.
+pub const MAX_POLYGON_POINTS: usize = 256;
+
+/// Which pixels are inside a polygon whose edges cross each other.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FillRule {
+    /// Inside if a ray from the pixel crosses the border an odd number of times.
+    #[default]
+    EvenOdd,
+    /// Inside if the border winds around the pixel.
+    NonZero,
+}
+
+/// Closed polygon, a pixel is in it if its center is.
+pub struct Polygon {
+    points: Vec<PixelPos>,
+    /// Bounding box from `(x0, y0)` inclusive to `(x1, y1)` exclusive.
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+}
+
+impl Polygon {
+    /// The polygon through `start` and the points reached by each move,
+    /// the last point is joined back to `start`.
+    pub fn new(start: PixelPos, moves: &[Delta]) -> PaintResult<Self> {
+        let invalid = |msg: String| Err(PaintError::InvalidData(msg));
+        if moves.len() < 2 || moves.len() >= MAX_POLYGON_POINTS {
+            return invalid(format!(
+                "a polygon must have 3 to {} points",
+                MAX_POLYGON_POINTS
+            ));
+        }
+        let mut points = vec![start];
+        let mut p = start;
+        for d in moves.iter() {
+            p = match (p.x.checked_add(d.x as i64), p.y.checked_add(d.y as i64)) {
+                (Some(x), Some(y)) => PixelPos { x, y },
+                _ => return invalid("polygon out of range".to_owned()),
+            };
+            points.push(p);
+        }
+        let x0 = points.iter().map(|p| p.x).min().unwrap();
+        let y0 = points.iter().map(|p| p.y).min().unwrap();
+        let x1 = points.iter().map(|p| p.x).max().unwrap();
+        let y1 = points.iter().map(|p| p.y).max().unwrap();
+        if !span_fits(x0, x1, MAX_POLYGON_SIZE) || !span_fits(y0, y1, MAX_POLYGON_SIZE) {
+            return invalid(format!(
+                "a polygon must fit in {0}x{0} pixels",
+                MAX_POLYGON_SIZE
+            ));
+        }
+        Ok(Self {
+            points,
+            x0,
+            y0,
+            x1,
+            y1,
+        })
+    }
+
+    /// Spans `[x0, x1)` of pixels inside the polygon on each row from `self.y0`.
+    fn spans(&self, rule: FillRule) -> Vec<Vec<(i64, i64)>> {
+        (self.y0..self.y1)
+            .map(|y| row_spans(&self.points, y, rule))
+            .collect()
+    }
+
+    /// Pixels inside the polygon by the rule, block by block.
+    pub fn fill(&self, rule: FillRule) -> impl Iterator<Item = PixelPos> {
+        let spans = Arc::new(self.spans(rule));
+        let (x0, y0, y1) = (self.x0, self.y0, self.y1);
+        let (bx0, bx1) = (x0 >> BLOCK_BITS, (self.x1 - 1) >> BLOCK_BITS);
+        let (by0, by1) = (y0 >> BLOCK_BITS, (y1 - 1) >> BLOCK_BITS);
+        (bx0..=bx1)
+            .flat_map(move |bx| (by0..=by1).map(move |by| (bx, by)))
+            .flat_map(move |(bx, by)| {
+                let spans = spans.clone();
+                let ys = block_range(by, y0, y1 - 1);
+                ys.flat_map(move |y| {
+                    let row: Vec<(i64, i64)> = spans[(y - y0) as usize].clone();
+                    row.into_iter().flat_map(move |(sx0, sx1)| {
+                        let xs = block_range(bx, sx0, sx1 - 1);
+                        xs.map(move |x| PixelPos { x, y })
+                    })
+                })
+            })
+    }
+
+    /// Pixels of the border by Bresenham lines, each given once, block by block.
+    pub fn border(&self) -> Vec<PixelPos> {
+        // a line leaves out its end, which starts the next one
+        let mut pixels: Vec<PixelPos> = edges(&self.points)
+            .flat_map(|(a, b)| {
+                let d = Delta {
+                    x: (b.x - a.x) as i16,
+                    y: (b.y - a.y) as i16,
+                };
+                LineIter::new(a, d)
+            })
+            .collect();
+        pixels.sort_unstable_by_key(|p| (p.block().x, p.block().y, p.x, p.y));
+        pixels.dedup();
+        pixels
+    }
+}
+
+fn edges(points: &[PixelPos]) -> impl Iterator<Item = (PixelPos, PixelPos)> + '_ {
+    let n = points.len();
+    (0..n).map(move |i| (points[i], points[(i + 1) % n]))
+}
+
+/// Spans `[x0, x1)` of pixels on row `y` inside the closed polygon through `points`.
+pub fn row_spans(points: &[PixelPos], y: i64, rule: FillRule) -> Vec<(i64, i64)> {
+    // in doubled coordinates the center is odd and vertices are even,
+    // so a row of centers never passes a vertex
+    let cy = 2 * y as i128 + 1;
+    let mut crossings: Vec<(i64, i32)> = edges(points)
+        .filter_map(|(a, b)| {
+            let (ax, ay) = (2 * a.x as i128, 2 * a.y as i128);
+            let (bx, by) = (2 * b.x as i128, 2 * b.y as i128);
+            if (ay > cy) == (by > cy) {
+                return None;
+            }
+            // the edge crosses at `num / den`, the first pixel whose center
+            // is not left of it is `ceil((num - den) / 2den)`
+            let num = ax * (by - ay) + (cy - ay) * (bx - ax);
+            let (num, den, dir) = if by > ay {
+                (num, by - ay, 1)
+            } else {
+                (-num, ay - by, -1)
+            };
+            Some((ceil_div(num - den, 2 * den) as i64, dir))
+        })
+        .collect();
+    crossings.sort_unstable();
+
+    // a pixel is inside by the crossings on its right
+    let mut spans = Vec::new();
+    let mut winding: i32 = crossings.iter().map(|(_, dir)| dir).sum();
+    for (i, (x, dir)) in crossings.iter().enumerate() {
+        winding -= dir;
+        let inside = match rule {
+            FillRule::EvenOdd => (crossings.len() - i - 1) % 2 == 1,
+            FillRule::NonZero => winding != 0,
+        };
+        let end = crossings.get(i + 1).map_or(*x, |(x, _)| *x);
+        if inside && *x < end {
+            match spans.last_mut() {
+                Some((_, x1)) if *x1 == *x => *x1 = end,
+                _ => spans.push((*x, end)),
+            }
+        }
+    }
+    spans
+}
+
+fn ceil_div(a: i128, b: i128) -> i128 {
+    -((-a).div_euclid(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::claims::Shape;
+    use crate::paint::db::PaintDB;
+    use std::collections::HashSet;
+
+    fn polygon(points: &[(i64, i64)]) -> Polygon {
+        let start = PixelPos {
+            x: points[0].0,
+            y: points[0].1,
+        };
+        let moves: Vec<Delta> = points
+            .windows(2)
+            .map(|w| Delta {
+                x: (w[1].0 - w[0].0) as i16,
+                y: (w[1].1 - w[0].1) as i16,
+            })
+            .collect();
+        Polygon::new(start, &moves).unwrap()
+    }
+
+    fn pixels<I: IntoIterator<Item = PixelPos>>(pixels: I) -> HashSet<(i64, i64)> {
+        pixels.into_iter().map(|p| (p.x, p.y)).collect()
+    }
+
+    #[test]
+    fn test_fill() {
+        let square = polygon(&[(0, 0), (20, 0), (20, 20), (0, 20)]);
+        assert_eq!(square.fill(FillRule::EvenOdd).count(), 400);
+        assert_eq!(square.fill(FillRule::NonZero).count(), 400);
+        assert_eq!(square.border().len(), 80);
+
+        // the same pixels as a claim of the polygon
+        let points = [(-7, 3), (30, -20), (41, 17), (12, 40), (5, 9)];
+        let shape = Shape::Polygon(
+            points
+                .iter()
+                .map(|(x, y)| PixelPos { x: *x, y: *y })
+                .collect(),
+        );
+        let poly = polygon(&points);
+        let fill: Vec<PixelPos> = poly.fill(FillRule::EvenOdd).collect();
+        let filled = pixels(fill.iter().copied());
+        assert_eq!(filled.len(), fill.len());
+        assert_eq!(filled.len(), shape.area());
+        assert!(fill.iter().all(|p| shape.contains(*p)));
+        PaintDB::block_runs(&fill);
+    }
+
+    #[test]
+    fn test_edge() {
+        // the right half in the last block column of the canvas
+        let x = i64::MAX - 20;
+        let square = polygon(&[(x, 0), (x + 10, 0), (x + 10, 10), (x, 10)]);
+        let fill: Vec<PixelPos> = square.fill(FillRule::EvenOdd).collect();
+        assert_eq!(fill.len(), 100);
+        assert!(fill.iter().any(|p| p.x == x + 9));
+        PaintDB::block_runs(&fill);
+    }
+
+    #[test]
+    fn test_rules() {
+        // a pentagram, its center is wound twice
+        let star = polygon(&[(0, 0), (20, 60), (40, 0), (-10, 38), (50, 38)]);
+        let even = pixels(star.fill(FillRule::EvenOdd));
+        let nonzero = pixels(star.fill(FillRule::NonZero));
+        assert!(!even.contains(&(20, 28)));
+        assert!(nonzero.contains(&(20, 28)));
+        assert!(even.is_subset(&nonzero));
+        assert!(even.contains(&(20, 50)) && nonzero.contains(&(20, 50)));
+
+        let border = star.border();
+        assert_eq!(pixels(border.iter().copied()).len(), border.len());
+        assert!(pixels(border).contains(&(50, 38)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let start = PixelPos { x: 0, y: 0 };
+        assert!(Polygon::new(start, &[Delta { x: 1, y: 0 }]).is_err());
+        let far = [Delta { x: 2000, y: 0 }, Delta { x: 0, y: 5 }];
+        assert!(Polygon::new(start, &far).is_err());
+        let edge = PixelPos { x: i64::MAX, y: 0 };
+        assert!(Polygon::new(edge, &[Delta { x: 1, y: 0 }, Delta { x: 0, y: 1 }]).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use serde_derive::Deserialize;
+
+use super::data::{block_range, PixelPos, BLOCK_BITS};
+use super::error::{PaintError, PaintResult};
+
+/// Longest side of a rectangle drawn at once, in pixels.
+const MAX_RECT_SIZE: u32 = 1024;
+
+/// Pixels from `(x, y)` inclusive to `(x + w, y + h)` exclusive.
+#[derive(Deserialize, Clone, Copy)]
+pub struct PixelRect {
+    pub x: i64,
+    pub y: i64,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl PixelRect {
+    pub fn validate(&self) -> PaintResult<()> {
+        if self.w == 0 || self.h == 0 {
+            return Err(PaintError::InvalidData("empty rectangle".to_owned()));
+        }
+        if self.w > MAX_RECT_SIZE || self.h > MAX_RECT_SIZE {
+            return Err(PaintError::InvalidData(format!(
+                "a rectangle must fit in {0}x{0} pixels",
+                MAX_RECT_SIZE
+            )));
+        }
+        if self.x.checked_add(self.w as i64).is_none()
+            || self.y.checked_add(self.h as i64).is_none()
+        {
+            return Err(PaintError::InvalidData("rectangle out of range".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Pixels of the rectangle, or of its one pixel wide border if `outline`.
+    /// They are given block by block, blocks inside the border are skipped for an outline.
+    pub fn pixels(self, outline: bool) -> impl Iterator<Item = PixelPos> {
+        let (x0, y0) = (self.x, self.y);
+        let (x1, y1) = (x0 + self.w as i64, y0 + self.h as i64);
+        let (bx0, bx1) = (x0 >> BLOCK_BITS, (x1 - 1) >> BLOCK_BITS);
+        let (by0, by1) = (y0 >> BLOCK_BITS, (y1 - 1) >> BLOCK_BITS);
+        let on_border = move |x: i64, y: i64| x == x0 || x == x1 - 1 || y == y0 || y == y1 - 1;
+        (bx0..=bx1)
+            .flat_map(move |bx| (by0..=by1).map(move |by| (bx, by)))
+            .filter(move |(bx, by)| {
+                !outline || *bx == bx0 || *bx == bx1 || *by == by0 || *by == by1
+            })
+            .flat_map(move |(bx, by)| {
+                let xs = block_range(bx, x0, x1 - 1);
+                let ys = block_range(by, y0, y1 - 1);
+                xs.flat_map(move |x| ys.clone().map(move |y| PixelPos { x, y }))
+            })
+            .filter(move |p| !outline || on_border(p.x, p.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::db::PaintDB;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_rect() {
+        let rect = PixelRect {
+            x: -3,
+            y: 10,
+            w: 40,
+            h: 7,
+        };
+        assert!(rect.validate().is_ok());
+        let fill: Vec<PixelPos> = rect.pixels(false).collect();
+        assert_eq!(fill.len(), 40 * 7);
+        assert_eq!(PaintDB::block_runs(&fill), 4 * 2);
+
+        let outline: HashSet<(i64, i64)> = rect.pixels(true).map(|p| (p.x, p.y)).collect();
+        assert_eq!(outline.len(), 2 * 40 + 2 * 5);
+        assert!(outline.contains(&(-3, 10)) && outline.contains(&(36, 16)));
+        assert!(!outline.contains(&(0, 12)));
+
+        let thin = PixelRect { w: 1, ..rect };
+        assert_eq!(thin.pixels(true).count(), 7);
+        assert!(PixelRect { w: 0, ..rect }.validate().is_err());
+        assert!(PixelRect { w: 1025, ..rect }.validate().is_err());
+        assert!(PixelRect {
+            x: i64::MAX,
+            ..rect
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_edge() {
+        // the last block of the canvas, ending at i64::MAX
+        let rect = PixelRect {
+            x: i64::MAX - 10,
+            y: i64::MAX - 7,
+            w: 5,
+            h: 4,
+        };
+        assert!(rect.validate().is_ok());
+        let fill: Vec<PixelPos> = rect.pixels(false).collect();
+        assert_eq!(fill.len(), 5 * 4);
+        assert!(fill
+            .iter()
+            .any(|p| p.x == i64::MAX - 6 && p.y == i64::MAX - 4));
+        assert_eq!(rect.pixels(true).count(), 2 * 5 + 2 * 2);
+    }
+}
@@ -0,0 +1,183 @@
+use serde_derive::{Deserialize, Serialize};
+use zip::result::ZipError;
+
+use std::io::{Read, Seek, Write};
+
+use super::data::BlockInfo;
+use super::db::PaintDB;
+use super::error::{InternalError, PaintError, PaintResult};
+use super::store::{block_file_name, parse_block_file_name};
+
+// A snapshot is a zip archive holding `manifest.json` and one `blocks/{x}_{y}.blk`
+// per block, in the same format as the file store.
+
+const FORMAT: &str = "canvast-snapshot";
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const BLOCKS_DIR: &str = "blocks/";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    time: u64,
+    blocks: usize,
+}
+
+/// Dump every block of the canvas, return the number of blocks.
+pub async fn export<W: Write + Seek>(pdb: &PaintDB, w: W) -> PaintResult<usize> {
+    let time = pdb.now();
+    let blks = pdb.block_positions().await?;
+    let mut ziper = zip::ZipWriter::new(w);
+    for blk in blks.iter() {
+        let info = pdb.snapshot_block(*blk).await?;
+        let option = zip::write::FileOptions::default();
+        ziper
+            .start_file(format!("{}{}", BLOCKS_DIR, block_file_name(*blk)), option)
+            .map_err(InternalError::from)?;
+        info.save(&mut ziper)?;
+    }
+
+    let manifest = Manifest {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+        time,
+        blocks: blks.len(),
+    };
+    ziper
+        .start_file(MANIFEST, zip::write::FileOptions::default())
+        .map_err(InternalError::from)?;
+    let manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| InternalError::from(std::io::Error::from(e)))?;
+    ziper.write_all(&manifest).map_err(InternalError::from)?;
+    ziper.finish().map_err(InternalError::from)?;
+    Ok(blks.len())
+}
+
+/// Restore blocks from a snapshot, return the number of blocks.
+///
+/// Blocks in the snapshot replace the current ones, other blocks are kept.
+pub async fn import<R: Read + Seek>(pdb: &PaintDB, r: R) -> PaintResult<usize> {
+    let mut ziper = zip::ZipArchive::new(r).map_err(invalid)?;
+
+    let manifest: Manifest = {
+        let file = ziper.by_name(MANIFEST).map_err(invalid)?;
+        serde_json::from_reader(file).map_err(|_| invalid_msg("bad manifest"))?
+    };
+    if manifest.format != FORMAT || manifest.version != VERSION {
+        return Err(invalid_msg("unsupported snapshot version"));
+    }
+
+    let mut names = Vec::new();
+    for i in 0..ziper.len() {
+        names.push(ziper.by_index(i).map_err(invalid)?.name().to_owned());
+    }
+    if names.iter().filter(|n| n.starts_with(BLOCKS_DIR)).count() != manifest.blocks {
+        return Err(invalid_msg("block count mismatch"));
+    }
+
+    let mut cnt = 0;
+    for i in 0..ziper.len() {
+        let (blk, info) = {
+            let file = ziper.by_index(i).map_err(invalid)?;
+            let blk = match file.name().strip_prefix(BLOCKS_DIR) {
+                Some(name) => parse_block_file_name(name),
+                None => continue,
+            };
+            let blk = blk.ok_or_else(|| invalid_msg("bad block name"))?;
+            let info = BlockInfo::load(file).map_err(|_| invalid_msg("bad block"))?;
+            (blk, info)
+        };
+        pdb.restore_block(blk, info).await?;
+        cnt += 1;
+    }
+    Ok(cnt)
+}
+
+fn invalid(e: ZipError) -> PaintError {
+    PaintError::InvalidData(format!("bad snapshot: {}", e))
+}
+
+fn invalid_msg(msg: &str) -> PaintError {
+    PaintError::InvalidData(format!("bad snapshot: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::config::PaintConfig;
+    use crate::paint::data::{BlockPos, RGBA};
+    use crate::paint::store::{FileStore, MemStore};
+    use crate::paint::timestamp::FakeClock;
+    use crate::paint::wal::Wal;
+    use crate::user::Actor;
+    use hex::FromHex;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn memory_db() -> PaintDB {
+        PaintDB::new(
+            Box::new(MemStore::new()),
+            Arc::new(FakeClock::new(7)),
+            None,
+            PaintConfig::default(),
+        )
+    }
+
+    fn logged_db(dir: &Path, now: u64) -> PaintResult<PaintDB> {
+        let store = FileStore::open(dir.join("blocks")).map_err(InternalError::from)?;
+        let wal = Wal::open(dir.join("wal")).map_err(InternalError::from)?;
+        Ok(PaintDB::new(
+            Box::new(store),
+            Arc::new(FakeClock::new(now)),
+            Some(wal),
+            PaintConfig::default(),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_export_import() -> PaintResult<()> {
+        let src = memory_db();
+        let blks = [BlockPos { x: -1, y: 5 }, BlockPos { x: 3, y: 0 }];
+        let color = RGBA::from_hex("10203040")?;
+        assert!(src.set_lock("luffbee".to_owned(), blks[0]).await?);
+        assert_eq!(
+            src.draw_pixels(
+                &Actor::new("luffbee".to_owned()),
+                color,
+                vec![blks[1].into()]
+            )
+            .await?,
+            1
+        );
+        src.compact().await?;
+
+        let mut archive = Vec::new();
+        assert_eq!(export(&src, Cursor::new(&mut archive)).await?, 2);
+
+        let dir = std::env::temp_dir().join(format!("canvast-import-{}", std::process::id()));
+        let dst = logged_db(&dir, 50)?;
+        assert_eq!(dst.recover().await?, 0);
+        assert_eq!(import(&dst, Cursor::new(&archive)).await?, 2);
+        assert_eq!(dst.get_lock(blks[0]).await?.owner, "luffbee");
+        let (mut expect, mut actual) = (Vec::new(), Vec::new());
+        assert_eq!(src.get_block(blks[1], &mut expect, 0).await?, 7);
+        // restored blocks are newer than anything clients have read
+        assert!(dst.get_block(blks[1], &mut actual, 7).await? >= 50);
+        assert_eq!(expect, actual);
+        assert!(import(&dst, Cursor::new(&archive[1..])).await.is_err());
+        drop(dst); // crash without flushing
+
+        // the import is replayed from the log
+        let dst = logged_db(&dir, 0)?;
+        assert_eq!(dst.recover().await?, 2);
+        assert_eq!(dst.get_lock(blks[0]).await?.owner, "luffbee");
+        let mut recovered = Vec::new();
+        assert!(dst.get_block(blks[1], &mut recovered, 7).await? >= 50);
+        assert_eq!(expect, recovered);
+
+        std::fs::remove_dir_all(&dir).map_err(InternalError::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use actix_web::{error::BlockingError, web};
+use async_trait::async_trait;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use super::BlockStore;
+use crate::paint::data::{BlockInfo, BlockPos};
+use crate::paint::error::InternalError;
+use crate::paint::PaintResult;
+
+/// Stores every block in its own file, named `{x}_{y}.blk`, under a directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, blk: BlockPos) -> PathBuf {
+        self.dir.join(block_file_name(blk))
+    }
+}
+
+pub fn block_file_name(blk: BlockPos) -> String {
+    format!("{}_{}.blk", blk.x, blk.y)
+}
+
+pub fn parse_block_file_name(name: &str) -> Option<BlockPos> {
+    let mut xy = name.strip_suffix(".blk")?.splitn(2, '_');
+    let x = xy.next()?.parse().ok()?;
+    let y = xy.next()?.parse().ok()?;
+    Some(BlockPos { x, y })
+}
+
+#[async_trait]
+impl BlockStore for FileStore {
+    async fn load(&self, blk: BlockPos) -> PaintResult<Option<BlockInfo>> {
+        let path = self.path(blk);
+        blocking(move || match File::open(&path) {
+            Ok(file) => BlockInfo::load(BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn save(&self, blk: BlockPos, info: BlockInfo) -> PaintResult<()> {
+        let (dir, path) = (self.dir.clone(), self.path(blk));
+        let tmp = path.with_extension("tmp");
+        blocking(move || {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            info.save(&mut w)?;
+            let file = w.into_inner().map_err(|e| e.into_error())?;
+            // the block is on disk before it replaces the old one, and the rename
+            // is durable before the log that rebuilds the block may be compacted,
+            // so a crash never leaves a half written or lost block behind
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            sync_dir(&dir)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete(&self, blk: BlockPos) -> PaintResult<bool> {
+        let (dir, path) = (self.dir.clone(), self.path(blk));
+        blocking(move || match fs::remove_file(&path) {
+            Ok(()) => {
+                sync_dir(&dir)?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn list(&self) -> PaintResult<Vec<BlockPos>> {
+        let dir = self.dir.clone();
+        blocking(move || {
+            let mut blks = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                if let Some(blk) = entry?.file_name().to_str().and_then(parse_block_file_name) {
+                    blks.push(blk);
+                }
+            }
+            Ok(blks)
+        })
+        .await
+    }
+}
+
+/// Make the creation, renaming and removal of files in `dir` durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+async fn blocking<F, T>(f: F) -> PaintResult<T>
+where
+    F: FnOnce() -> Result<T, InternalError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e.into(),
+        BlockingError::Canceled => InternalError::BlockStoreCanceled.into(),
+    })
+}
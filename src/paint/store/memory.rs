@@ -0,0 +1,41 @@
+use async_trait::async_trait;
+use parking_lot::RwLock;
+
+use std::collections::HashMap;
+
+use super::BlockStore;
+use crate::paint::data::{BlockInfo, BlockPos};
+use crate::paint::PaintResult;
+
+/// Keeps saved blocks in memory, they are lost on restart.
+pub struct MemStore {
+    blocks: RwLock<HashMap<BlockPos, BlockInfo>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self {
+            blocks: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockStore for MemStore {
+    async fn load(&self, blk: BlockPos) -> PaintResult<Option<BlockInfo>> {
+        Ok(self.blocks.read().get(&blk).cloned())
+    }
+
+    async fn save(&self, blk: BlockPos, info: BlockInfo) -> PaintResult<()> {
+        self.blocks.write().insert(blk, info);
+        Ok(())
+    }
+
+    async fn delete(&self, blk: BlockPos) -> PaintResult<bool> {
+        Ok(self.blocks.write().remove(&blk).is_some())
+    }
+
+    async fn list(&self) -> PaintResult<Vec<BlockPos>> {
+        Ok(self.blocks.read().keys().copied().collect())
+    }
+}
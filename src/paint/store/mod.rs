@@ -0,0 +1,68 @@
+use async_trait::async_trait;
+
+use super::data::{BlockInfo, BlockPos};
+use super::PaintResult;
+
+mod file;
+pub use file::{block_file_name, parse_block_file_name, FileStore};
+mod memory;
+pub use memory::MemStore;
+
+/// Persistent storage of blocks behind `PaintDB`.
+#[async_trait]
+pub trait BlockStore: Send + Sync {
+    /// Load a block, `None` if it has never been saved.
+    async fn load(&self, blk: BlockPos) -> PaintResult<Option<BlockInfo>>;
+    async fn save(&self, blk: BlockPos, info: BlockInfo) -> PaintResult<()>;
+    /// Delete a block, return whether it existed.
+    #[allow(dead_code)]
+    async fn delete(&self, blk: BlockPos) -> PaintResult<bool>;
+    async fn list(&self) -> PaintResult<Vec<BlockPos>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paint::data::RGBA;
+    use crate::paint::error::InternalError;
+    use hex::FromHex;
+
+    async fn check_store(store: &dyn BlockStore) -> PaintResult<()> {
+        let blk = BlockPos { x: -3, y: 7 };
+        assert!(store.load(blk).await?.is_none());
+        assert!(store.list().await?.is_empty());
+
+        let mut info = BlockInfo::new();
+        info.set_owner("luffbee".to_owned(), 1000);
+        info.draw_pixels(RGBA::from_hex("A3A3A3FF")?, vec![(1, 2)], 1);
+        store.save(blk, info.clone()).await?;
+        assert_eq!(store.list().await?, vec![blk]);
+
+        let loaded = store.load(blk).await?.unwrap();
+        assert_eq!(loaded.get_owner(), "luffbee");
+        let (mut expect, mut actual) = (Vec::new(), Vec::new());
+        assert_eq!(
+            info.block_to_png(&mut expect, 0)?,
+            loaded.block_to_png(&mut actual, 0)?
+        );
+        assert_eq!(expect, actual);
+
+        assert!(store.delete(blk).await?);
+        assert!(!store.delete(blk).await?);
+        assert!(store.load(blk).await?.is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_mem_store() -> PaintResult<()> {
+        check_store(&MemStore::new()).await
+    }
+
+    #[actix_rt::test]
+    async fn test_file_store() -> PaintResult<()> {
+        let dir = std::env::temp_dir().join(format!("canvast-store-{}", std::process::id()));
+        check_store(&FileStore::open(&dir).map_err(InternalError::from)?).await?;
+        std::fs::remove_dir_all(dir).map_err(InternalError::from)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,123 @@
-use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::time::Instant;
+/// Source of block modification times, in milliseconds since the Unix epoch.
+pub trait Clock: Send + Sync {
+    /// Current time, strictly greater than any time returned or observed before.
+    fn now(&self) -> u64;
+    /// Current time without advancing the clock, for checking expiries.
+    fn current(&self) -> u64;
+    /// Make sure later `now()` is greater than `ts`, e.g. an mtime loaded from disk.
+    fn observe(&self, ts: u64);
+}
+
+/// Wall clock with a monotonic guard, so that it never goes backwards
+/// even if the system time does, or a block was written by a faster clock.
+pub struct SystemClock {
+    last: AtomicU64,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let wall = unix_millis();
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let ts = wall.max(last + 1);
+            match self
+                .last
+                .compare_exchange_weak(last, ts, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return ts,
+                Err(cur) => last = cur,
+            }
+        }
+    }
+
+    fn current(&self) -> u64 {
+        unix_millis().max(self.last.load(Ordering::Relaxed))
+    }
+
+    fn observe(&self, ts: u64) {
+        self.last.fetch_max(ts, Ordering::Relaxed);
+    }
+}
 
-lazy_static! {
-    static ref BOOT_INSTANT: Instant = Instant::now();
+/// Clock that only moves when told to, for tests.
+#[cfg(test)]
+pub struct FakeClock {
+    now: AtomicU64,
 }
 
-pub fn now() -> u64 {
-    BOOT_INSTANT.elapsed().as_millis() as u64
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn current(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn observe(&self, ts: u64) {
+        self.now.fetch_max(ts + 1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock() {
+        let clock = SystemClock::new();
+        let t0 = clock.now();
+        assert!(t0 >= unix_millis() - 1000);
+        let t1 = clock.now();
+        assert!(t1 > t0);
+        assert!(clock.current() >= t1);
+
+        let future = unix_millis() + 3_600_000;
+        clock.observe(future);
+        assert!(clock.now() > future);
+        clock.observe(t0);
+        assert!(clock.now() > future + 1);
+
+        // polling the time does not push it ahead
+        let clock = SystemClock::new();
+        for _ in 0..10_000 {
+            clock.observe(clock.current());
+        }
+        assert!(clock.current() <= unix_millis());
+        let ts = clock.current();
+        clock.observe(ts);
+        assert!(clock.now() > ts);
+    }
 }
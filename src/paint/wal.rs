@@ -0,0 +1,492 @@
+use log::{error, warn};
+use parking_lot::{Condvar, Mutex};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::user::Username;
+
+use super::claims::{Claim, ClaimIndex};
+use super::data::{BlockInfo, BlockPos, Offset, RGBABlock, RGBA};
+use super::error::InternalError;
+
+/// A mutation of a single block, already checked against block locks,
+/// or a change of the pixel claims.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Op {
+    DrawPixels {
+        blk: BlockPos,
+        color: RGBA,
+        offsets: Vec<Offset>,
+        ts: u64,
+    },
+    DrawBlock {
+        blk: BlockPos,
+        image: RGBABlock,
+        ts: u64,
+    },
+    /// Replace the whole block, encoded by `BlockInfo::save`, as of `ts`.
+    RestoreBlock {
+        blk: BlockPos,
+        block: Vec<u8>,
+        ts: u64,
+    },
+    ResetOwner {
+        blk: BlockPos,
+    },
+    /// Lock or renew a lock until `expire`.
+    SetLease {
+        blk: BlockPos,
+        owner: Username,
+        expire: u64,
+    },
+    Grant {
+        blk: BlockPos,
+        user: Username,
+    },
+    Revoke {
+        blk: BlockPos,
+        user: Username,
+    },
+    /// Add a claim, or replace the one with the same id.
+    Claim {
+        claim: Claim,
+    },
+    Unclaim {
+        id: u64,
+    },
+}
+
+impl Op {
+    /// The block the operation changes, `None` for claims.
+    pub fn block(&self) -> Option<BlockPos> {
+        use Op::*;
+        match self {
+            DrawPixels { blk, .. } | DrawBlock { blk, .. } | RestoreBlock { blk, .. } => Some(*blk),
+            ResetOwner { blk } | SetLease { blk, .. } | Grant { blk, .. } | Revoke { blk, .. } => {
+                Some(*blk)
+            }
+            Claim { .. } | Unclaim { .. } => None,
+        }
+    }
+
+    pub fn apply(&self, info: &mut BlockInfo) {
+        use Op::*;
+        match self {
+            DrawPixels {
+                color, offsets, ts, ..
+            } => info.draw_pixels(*color, offsets.iter().copied(), *ts),
+            DrawBlock { image, ts, .. } => info.draw_block(image, *ts),
+            RestoreBlock { blk, block, ts } => match BlockInfo::load(&block[..]) {
+                Ok(restored) => info.restore(restored, *ts),
+                Err(e) => error!("skip restoring block {:?}: {}", blk, e),
+            },
+            ResetOwner { .. } => info.reset_owner(),
+            SetLease { owner, expire, .. } => info.set_owner(owner.clone(), *expire),
+            Grant { user, .. } => {
+                info.grant(user);
+            }
+            Revoke { user, .. } => {
+                info.revoke(user);
+            }
+            Claim { .. } | Unclaim { .. } => (),
+        }
+    }
+
+    pub fn apply_claims(&self, claims: &mut ClaimIndex) {
+        match self {
+            Op::Claim { claim } => claims.insert(claim.clone()),
+            Op::Unclaim { id } => {
+                claims.remove(*id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether the operation changes the lock of the block.
+    pub fn is_lock(&self) -> bool {
+        matches!(
+            self,
+            Op::RestoreBlock { .. } | Op::ResetOwner { .. } | Op::SetLease { .. }
+        )
+    }
+
+    /// Whether the block already contains this operation, so replaying must skip it.
+    ///
+    /// Drawings carry the new mtime, which the clock keeps strictly increasing.
+    /// Owner, collaborator and claim changes are idempotent and always replayed.
+    pub fn applied(&self, info: &BlockInfo) -> bool {
+        match self {
+            Op::DrawPixels { ts, .. } | Op::DrawBlock { ts, .. } | Op::RestoreBlock { ts, .. } => {
+                *ts <= info.mtime()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Append-only operation log, split into numbered segment files.
+///
+/// Each record is `length: u32, crc32: u32, payload`, where the payload is a
+/// bincode encoded `Vec<Op>` that must be replayed as a whole.
+/// Appending only queues a record, a writer thread writes all queued records at once
+/// to the OS, so that they survive a process crash once `written` returns.
+pub struct Wal {
+    dir: PathBuf,
+    shared: Arc<Shared>,
+    written: watch::Receiver<Written>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Number of records written so far, or `Err` once a write failed,
+/// after which no record is known to be written.
+type Written = Result<u64, ()>;
+
+struct Shared {
+    /// Locked before `pending`, so that records are taken and written in order.
+    segment: Mutex<Segment>,
+    pending: Mutex<Pending>,
+    /// Wakes up the writer when records are queued or the log is closed.
+    wake: Condvar,
+    written: watch::Sender<Written>,
+}
+
+struct Segment {
+    id: u64,
+    file: File,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct Pending {
+    records: Vec<u8>,
+    /// Number of records ever appended.
+    appended: u64,
+    closed: bool,
+}
+
+impl Shared {
+    /// Write all queued records to the segment, then tell the waiters.
+    fn write(&self, segment: &mut Segment) -> io::Result<()> {
+        let (records, appended) = {
+            let mut pending = self.pending.lock();
+            (std::mem::take(&mut pending.records), pending.appended)
+        };
+        if records.is_empty() {
+            return Ok(());
+        }
+        let result = segment.file.write_all(&records);
+        segment.failed |= result.is_err();
+        let written = if segment.failed {
+            Err(())
+        } else {
+            Ok(appended)
+        };
+        // nobody waiting is fine
+        let _ = self.written.broadcast(written);
+        result
+    }
+}
+
+fn write_loop(shared: Arc<Shared>) {
+    loop {
+        {
+            let mut pending = shared.pending.lock();
+            while pending.records.is_empty() && !pending.closed {
+                shared.wake.wait(&mut pending);
+            }
+            if pending.records.is_empty() {
+                return;
+            }
+        }
+        if let Err(e) = shared.write(&mut shared.segment.lock()) {
+            error!("failed to write the operation log: {}", e);
+        }
+    }
+}
+
+const SEGMENT_EXT: &str = "wal";
+const CHECKPOINT: &str = "checkpoint";
+const MAX_RECORD_LEN: u32 = 64 << 20;
+
+impl Wal {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        // never append to an old segment, its tail may be torn
+        let id = segment_ids(&dir)?.last().map_or(0, |id| id + 1);
+        let file = create_segment(&dir, id)?;
+        let (tx, written) = watch::channel(Ok(0));
+        let shared = Arc::new(Shared {
+            segment: Mutex::new(Segment {
+                id,
+                file,
+                failed: false,
+            }),
+            pending: Mutex::new(Pending::default()),
+            wake: Condvar::new(),
+            written: tx,
+        });
+        let writer = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("canvast-wal".to_owned())
+                .spawn(move || write_loop(shared))?
+        };
+        Ok(Self {
+            dir,
+            shared,
+            written,
+            writer: Some(writer),
+        })
+    }
+
+    /// Queue the operations as a record, without waiting for it to be written.
+    /// Records are written in the order they are appended.
+    pub fn append(&self, ops: &[Op]) -> Result<(), InternalError> {
+        let payload = bincode::serialize(ops)?;
+        let mut pending = self.shared.pending.lock();
+        pending
+            .records
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        pending
+            .records
+            .extend_from_slice(&checksum(&payload).to_le_bytes());
+        pending.records.extend_from_slice(&payload);
+        pending.appended += 1;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Wait until every record appended so far is written.
+    pub async fn written(&self) -> Result<(), InternalError> {
+        let appended = self.shared.pending.lock().appended;
+        let mut rx = self.written.clone();
+        // the first recv returns the current value at once
+        while let Some(written) = rx.recv().await {
+            match written {
+                Ok(n) if n < appended => (),
+                Ok(_) => return Ok(()),
+                Err(()) => break,
+            }
+        }
+        Err(InternalError::LogWriteFailed)
+    }
+
+    /// Start a new segment, return ids of all the older ones.
+    /// Records appended before are in the older ones.
+    pub fn rotate(&self) -> io::Result<Vec<u64>> {
+        let mut current = self.shared.segment.lock();
+        self.shared.write(&mut current)?;
+        current.file.sync_data()?;
+        let id = current.id + 1;
+        current.file = create_segment(&self.dir, id)?;
+        current.id = id;
+        drop(current);
+        Ok(segment_ids(&self.dir)?
+            .into_iter()
+            .filter(|i| *i < id)
+            .collect())
+    }
+
+    /// Remove segments whose operations are all saved in the block store.
+    pub fn remove(&self, ids: &[u64]) -> io::Result<()> {
+        for id in ids {
+            fs::remove_file(segment_path(&self.dir, *id))?;
+        }
+        Ok(())
+    }
+
+    /// Save state derived from the log, it must cover every operation in segments removed after.
+    pub fn save_checkpoint(&self, data: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        fs::rename(tmp, self.dir.join(CHECKPOINT))
+    }
+
+    /// The last saved checkpoint, `None` if never saved.
+    pub fn load_checkpoint(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(CHECKPOINT)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read all operations in the given segments, in the order they were logged.
+    pub fn read(&self, ids: &[u64]) -> Result<Vec<Op>, InternalError> {
+        let mut ops = Vec::new();
+        for id in ids {
+            let path = segment_path(&self.dir, *id);
+            let mut r = BufReader::new(File::open(&path)?);
+            loop {
+                match read_record(&mut r) {
+                    Ok(Some(mut record)) => ops.append(&mut record),
+                    Ok(None) => break,
+                    Err(e) => {
+                        // a torn write when the process died, nothing after it was acknowledged
+                        warn!("stop replaying {}: {}", path.display(), e);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
+
+impl Drop for Wal {
+    /// Write the queued records before closing.
+    fn drop(&mut self) {
+        self.shared.pending.lock().closed = true;
+        self.shared.wake.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn read_record<R: Read>(r: &mut R) -> Result<Option<Vec<Op>>, InternalError> {
+    let mut header = [0u8; 8];
+    match r.read_exact(&mut header[..1]) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    r.read_exact(&mut header[1..])?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len > MAX_RECORD_LEN {
+        return Err(InternalError::CorruptedLog);
+    }
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+    if checksum(&payload) != crc {
+        return Err(InternalError::CorruptedLog);
+    }
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", id, SEGMENT_EXT))
+}
+
+fn create_segment(dir: &Path, id: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(segment_path(dir, id))
+}
+
+fn segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+
+    #[test]
+    fn test_append_read() -> Result<(), InternalError> {
+        let dir = std::env::temp_dir().join(format!("canvast-wal-{}", std::process::id()));
+        let blk = BlockPos { x: 1, y: 2 };
+        let color = RGBA::from_hex("A3A3A3FF").unwrap();
+
+        let wal = Wal::open(&dir)?;
+        wal.append(&[Op::SetLease {
+            blk,
+            owner: "luffbee".to_owned(),
+            expire: 20,
+        }])?;
+        wal.append(&[
+            Op::DrawPixels {
+                blk,
+                color,
+                offsets: vec![(0, 0), (3, 4)],
+                ts: 10,
+            },
+            Op::ResetOwner { blk },
+        ])?;
+        let old = wal.rotate()?;
+        assert_eq!(old, vec![0]);
+        wal.append(&[Op::ResetOwner { blk }])?;
+
+        // a torn record at the tail is ignored
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 0))?;
+        file.write_all(&[42, 0, 0, 0, 1])?;
+
+        let ops = wal.read(&old)?;
+        assert_eq!(ops.len(), 3);
+        let mut info = BlockInfo::new();
+        for op in ops.iter() {
+            assert_eq!(op.block(), Some(blk));
+            assert!(!op.applied(&info));
+            op.apply(&mut info);
+        }
+        assert_eq!(info.mtime(), 10);
+        assert_eq!(info.get_owner(), "");
+        assert!(ops[1].applied(&info));
+
+        wal.remove(&old)?;
+        assert!(wal.load_checkpoint()?.is_none());
+        wal.save_checkpoint(b"locks")?;
+        drop(wal);
+        let wal = Wal::open(&dir)?;
+        assert_eq!(wal.rotate()?, vec![1, 2]);
+        assert_eq!(wal.read(&[1, 2])?.len(), 1);
+        assert_eq!(wal.load_checkpoint()?, Some(b"locks".to_vec()));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_written() -> Result<(), InternalError> {
+        let dir = std::env::temp_dir().join(format!("canvast-wal-written-{}", std::process::id()));
+        let blk = BlockPos { x: 0, y: 0 };
+
+        let wal = Wal::open(&dir)?;
+        wal.written().await?;
+        for _ in 0..100 {
+            wal.append(&[Op::ResetOwner { blk }])?;
+        }
+        wal.written().await?;
+        // written records are in the segment file without rotating
+        assert_eq!(wal.read(&[0])?.len(), 100);
+
+        drop(wal);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
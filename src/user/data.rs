@@ -7,22 +7,165 @@ use std::ops::RangeInclusive;
 use super::{UserError, UserResult};
 
 pub type Username = String;
+pub type Teamname = String;
+
+/// Locks taken on behalf of a team are owned by `team:<name>`,
+/// which never collides with a username.
+const TEAM_PREFIX: &str = "team:";
+
+pub fn team_owner(team: &str) -> Username {
+    format!("{}{}", TEAM_PREFIX, team)
+}
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct User {
     pub name: Username,
+    /// Only changed by admins, never taken from requests.
+    #[serde(skip_deserializing)]
+    pub role: UserRole,
+}
+
+/// Role of a user on the whole site, a higher role can do everything a lower one can.
+/// Moderators unlock blocks and ban users, admins also set roles and draw through locks.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    #[default]
+    User = 0,
+    Moderator = 1,
+    Admin = 2,
+}
+
+impl UserRole {
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(UserRole::User),
+            1 => Some(UserRole::Moderator),
+            2 => Some(UserRole::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewUserRole {
+    pub role: UserRole,
 }
 
 impl User {
     pub fn validate(&self) -> UserResult<()> {
-        // validate name
-        const LEN: RangeInclusive<usize> = 1..=64;
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"^[a-zA-Z][-.@\w]*$").unwrap();
+        validate_name(&self.name)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Team {
+    pub name: Teamname,
+}
+
+impl Team {
+    pub fn validate(&self) -> UserResult<()> {
+        validate_name(&self.name)
+    }
+}
+
+/// Role of a member in a team, a higher role can do everything a lower one can.
+/// Members draw on blocks locked by the team, admins also invite users and
+/// manage the locks of the team, the owner also sets roles.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member = 0,
+    Admin = 1,
+    Owner = 2,
+}
+
+impl Role {
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Role::Member),
+            1 => Some(Role::Admin),
+            2 => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewRole {
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct Member {
+    pub name: Username,
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct Membership {
+    pub team: Teamname,
+    pub role: Role,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestKind {
+    /// The sender hands its locks over to the recipient.
+    Transfer = 0,
+    /// The sender asks the lock owner for the right to draw.
+    Access = 1,
+}
+
+impl RequestKind {
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(RequestKind::Transfer),
+            1 => Some(RequestKind::Access),
+            _ => None,
+        }
+    }
+}
+
+/// A request about the locks of blocks in a rectangle, waiting for the answer of `recipient`.
+#[derive(Serialize, Clone)]
+pub struct LockRequest {
+    pub id: i32,
+    pub kind: RequestKind,
+    pub sender: Username,
+    pub recipient: Username,
+    pub x: i64,
+    pub y: i64,
+    pub w: u8,
+    pub h: u8,
+}
+
+/// A user together with the teams it belongs to.
+pub struct Actor {
+    pub name: Username,
+    pub teams: Vec<Teamname>,
+    /// Draw through locks and claims of others, for admins cleaning up.
+    pub overrides: bool,
+}
+
+impl Actor {
+    pub fn new(name: Username) -> Self {
+        Self {
+            name,
+            teams: Vec::new(),
+            overrides: false,
+        }
+    }
+
+    /// Whether a lock of `owner` is held by the user itself or one of its teams.
+    pub fn owns(&self, owner: &str) -> bool {
+        if owner == self.name {
+            return true;
+        }
+        match owner.strip_prefix(TEAM_PREFIX) {
+            Some(team) => self.teams.iter().any(|t| t == team),
+            None => false,
         }
-        validate_length("name", &self.name, &LEN)?;
-        validate_pattern("name", &self.name, &RE)?;
-        Ok(())
     }
 }
 
@@ -46,6 +189,16 @@ impl WithPassword {
     }
 }
 
+/// Users and teams share the same naming rules.
+fn validate_name(name: &str) -> UserResult<()> {
+    const LEN: RangeInclusive<usize> = 1..=64;
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-zA-Z][-.@\w]*$").unwrap();
+    }
+    validate_length("name", name, &LEN)?;
+    validate_pattern("name", name, &RE)
+}
+
 fn validate_length(name: &str, value: &str, len: &RangeInclusive<usize>) -> Result<(), UserError> {
     if !len.contains(&value.len()) {
         Err(UserError::InvalidData(format!(
@@ -79,36 +232,58 @@ mod tests {
     fn test_validate_user() -> UserResult<()> {
         let u = User {
             name: "root".to_owned(),
+            role: UserRole::User,
         };
         u.validate()?;
         let u = User {
             name: "A".to_owned(),
+            role: UserRole::User,
         };
         u.validate()?;
         let u = User {
             name: "aA-.1_1@333".to_owned(),
+            role: UserRole::User,
         };
         u.validate()?;
         let u = User {
             name: "a".repeat(64),
+            role: UserRole::User,
         };
         u.validate()?;
         let u = User {
             name: "".to_owned(),
+            role: UserRole::User,
         };
-        assert_eq!(u.validate().is_err(), true);
+        assert!(u.validate().is_err());
         let u = User {
             name: "1244".to_owned(),
+            role: UserRole::User,
         };
-        assert_eq!(u.validate().is_err(), true);
+        assert!(u.validate().is_err());
         let u = User {
             name: "a+jjjjj".to_owned(),
+            role: UserRole::User,
         };
-        assert_eq!(u.validate().is_err(), true);
+        assert!(u.validate().is_err());
         let u = User {
             name: "a".repeat(65),
+            role: UserRole::User,
         };
-        assert_eq!(u.validate().is_err(), true);
+        assert!(u.validate().is_err());
         Ok(())
     }
+
+    #[test]
+    fn test_actor() {
+        let actor = Actor {
+            teams: vec!["artists".to_owned()],
+            ..Actor::new("luffbee".to_owned())
+        };
+        assert!(actor.owns("luffbee"));
+        assert!(actor.owns(&team_owner("artists")));
+        assert!(!actor.owns(&team_owner("luffbee")));
+        assert!(!actor.owns("artists"));
+        assert!(!Actor::new("sam".to_owned()).owns(&team_owner("artists")));
+        assert!(Role::Owner > Role::Admin && Role::Admin > Role::Member);
+    }
 }
@@ -1,92 +1,244 @@
-use parking_lot::RwLock;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use parking_lot::Mutex;
 use rand::{thread_rng, Rng};
 
-use std::collections::HashMap;
-use time::{Duration, Tm};
+use time::{Duration, Timespec, Tm};
 
 use crate::paint::PixelPos;
 
 use super::data::*;
+use super::error::InternalError;
+use super::password;
+use super::schema::{invites, locations, lock_requests, members, teams, tokens, users};
 use super::{UserError, UserResult};
 
 const TIMEOUT: i64 = 30; // 30 days
+/// Pending lock requests a recipient may have.
+const MAX_INBOX: i64 = 64;
+/// Pending lock requests a sender may have.
+const MAX_SENT: i64 = 32;
+
+embed_migrations!();
 
 type Token = String;
 
-pub struct UserDB(RwLock<SimpleDB>);
+pub struct UserDB(Mutex<SqliteDB>);
 
 impl UserDB {
-    pub fn new() -> Self {
-        Self(RwLock::new(SimpleDB::new()))
+    /// Open the SQLite database at `url`, creating and migrating it if needed.
+    pub fn open(url: &str) -> Result<Self, InternalError> {
+        Ok(Self(Mutex::new(SqliteDB::open(url)?)))
     }
 
     pub async fn new_user(&self, user: WithPassword) -> UserResult<()> {
-        self.0.write().new_user(user)
+        let hash = hash(user.password).await?;
+        self.0.lock().new_user(&user.user, &hash)
     }
 
+    /// Passwords are checked off the executor and without holding the database.
     pub async fn login(&self, user: &WithPassword) -> UserResult<(Token, Tm)> {
-        self.0.write().login(user)
+        let name = &user.user.name;
+        let stored = self.0.lock().password(name)?;
+        let (password, known) = (user.password.clone(), stored.clone());
+        let matched = password::blocking(move || match known {
+            Some(known) => password::verify(&password, &known),
+            None => {
+                // spend the same time as a wrong password
+                password::verify(&password, password::dummy_hash())?;
+                Ok(false)
+            }
+        })
+        .await?;
+        let stored = match stored {
+            Some(stored) if matched => stored,
+            _ => return Err(UserError::LoginFailed),
+        };
+        let rehash = if password::needs_rehash(&stored) {
+            Some(hash(user.password.clone()).await?)
+        } else {
+            None
+        };
+        self.0.lock().login(name, rehash.as_deref())
     }
 
-    pub async fn check_token(&self, token: &str) -> UserResult<Username> {
-        self.0.read().check_token(token)
+    /// The user logged in with the token, banned users are rejected.
+    pub async fn check_token(&self, token: &str) -> UserResult<User> {
+        self.0.lock().check_token(token)
     }
 
     pub async fn logout(&self, token: &str) -> UserResult<()> {
-        self.0.write().logout(token)
+        self.0.lock().logout(token)
     }
 
     pub async fn set_location(&self, name: Username, loc: PixelPos) -> UserResult<()> {
-        self.0.write().set_location(name, loc)
+        self.0.lock().set_location(name, loc)
     }
 
     pub async fn get_location(&self, name: &str) -> UserResult<PixelPos> {
-        self.0.read().get_location(name)
+        self.0.lock().get_location(name)
     }
-}
 
-struct TokenInfo {
-    name: Username,
-    expire: Tm,
-}
+    pub async fn exists(&self, name: &str) -> UserResult<bool> {
+        self.0.lock().exists(name)
+    }
 
-struct SimpleDB {
-    users: HashMap<String, WithPassword>,
-    tokens: HashMap<Token, TokenInfo>,
-    locations: HashMap<String, PixelPos>,
-}
+    pub async fn get_user(&self, name: &str) -> UserResult<User> {
+        self.0.lock().get_user(name).map(|(user, _)| user)
+    }
 
-impl SimpleDB {
-    fn new() -> Self {
-        Self {
-            users: HashMap::new(),
-            tokens: HashMap::new(),
-            locations: HashMap::new(),
+    pub async fn set_user_role(&self, name: &str, role: UserRole) -> UserResult<()> {
+        self.0.lock().set_user_role(name, role)
+    }
+
+    /// Ban or unban a user, a banned user is logged out everywhere.
+    pub async fn set_banned(&self, name: &str, banned: bool) -> UserResult<()> {
+        self.0.lock().set_banned(name, banned)
+    }
+
+    /// Make `name` an admin if it does not exist yet, signing it up with `password`,
+    /// or if there is no admin at all, so that a later demotion survives restarts.
+    /// Return whether it was made an admin.
+    pub async fn bootstrap_admin(&self, name: &str, password: Option<String>) -> UserResult<bool> {
+        if self.exists(name).await? {
+            return self.0.lock().promote_first_admin(name);
         }
+        let user = WithPassword {
+            user: User {
+                name: name.to_owned(),
+                role: UserRole::Admin,
+            },
+            password: password.ok_or(UserError::UserNotFound)?,
+        };
+        user.validate()?;
+        self.new_user(user).await?;
+        self.0.lock().set_user_role(name, UserRole::Admin)?;
+        Ok(true)
     }
 
-    fn has_user(&self, name: &str) -> UserResult<bool> {
-        match self.users.get(name) {
-            Some(_) => Ok(true),
-            None => Ok(false),
+    /// The user with the teams it belongs to.
+    pub async fn actor(&self, name: Username) -> UserResult<Actor> {
+        let teams = self.0.lock().teams_of(&name)?;
+        Ok(Actor {
+            teams: teams.into_iter().map(|m| m.team).collect(),
+            ..Actor::new(name)
+        })
+    }
+
+    /// The lock owner `user` acts as: itself, or `team` if it is an admin of the team.
+    pub async fn act_as(&self, user: Username, team: Option<&str>) -> UserResult<Username> {
+        match team {
+            None => Ok(user),
+            Some(team) => {
+                self.0.lock().check_role(team, &user, Role::Admin)?;
+                Ok(team_owner(team))
+            }
         }
     }
 
-    fn new_user(&mut self, user: WithPassword) -> UserResult<()> {
-        if self.has_user(&user.user.name)? {
-            return Err(UserError::UserAlreadyExist);
+    /// Create a team owned by `owner`.
+    pub async fn new_team(&self, owner: &str, team: Team) -> UserResult<()> {
+        self.0.lock().new_team(owner, team)
+    }
+
+    pub async fn teams_of(&self, name: &str) -> UserResult<Vec<Membership>> {
+        self.0.lock().teams_of(name)
+    }
+
+    /// Members of the team, only visible to members.
+    pub async fn members(&self, team: &str, by: &str) -> UserResult<Vec<Member>> {
+        self.0.lock().members(team, by)
+    }
+
+    /// Invite `name` to the team, `by` must be an admin of the team.
+    pub async fn invite(&self, team: &str, by: &str, name: &str) -> UserResult<()> {
+        self.0.lock().invite(team, by, name)
+    }
+
+    /// Teams that invited the user.
+    pub async fn invites(&self, name: &str) -> UserResult<Vec<Teamname>> {
+        self.0.lock().invites(name)
+    }
+
+    /// Accept an invitation to join the team, or decline it.
+    pub async fn answer_invite(&self, team: &str, name: &str, accept: bool) -> UserResult<()> {
+        self.0.lock().answer_invite(team, name, accept)
+    }
+
+    /// Remove `name` from the team, either it leaves or `by` has a higher role.
+    /// The team is dropped when the last member leaves, return whether it is.
+    pub async fn remove_member(&self, team: &str, by: &str, name: &str) -> UserResult<bool> {
+        self.0.lock().remove_member(team, by, name)
+    }
+
+    /// Leave a request in the inbox of `request.recipient`, `request.id` is ignored.
+    /// Sending the same request again returns the pending one.
+    pub async fn send_request(&self, request: LockRequest) -> UserResult<LockRequest> {
+        self.0.lock().send_request(request)
+    }
+
+    /// Requests waiting for the answer of `recipient`, oldest first.
+    pub async fn inbox(&self, recipient: &str) -> UserResult<Vec<LockRequest>> {
+        self.0.lock().inbox(recipient)
+    }
+
+    pub async fn get_request(&self, id: i32) -> UserResult<LockRequest> {
+        self.0.lock().get_request(id)
+    }
+
+    pub async fn del_request(&self, id: i32) -> UserResult<()> {
+        self.0.lock().del_request(id)
+    }
+
+    /// Set the role of a member, `by` must be the owner.
+    /// Making another member the owner hands the team over, `by` becomes an admin.
+    pub async fn set_role(&self, team: &str, by: &str, name: &str, role: Role) -> UserResult<()> {
+        self.0.lock().set_role(team, by, name, role)
+    }
+}
+
+struct SqliteDB {
+    conn: SqliteConnection,
+}
+
+impl SqliteDB {
+    fn open(url: &str) -> Result<Self, InternalError> {
+        let conn = SqliteConnection::establish(url)?;
+        embedded_migrations::run(&conn)?;
+        Ok(Self { conn })
+    }
+
+    fn new_user(&mut self, user: &User, hash: &str) -> UserResult<()> {
+        let ret = diesel::insert_into(users::table)
+            .values((users::name.eq(&user.name), users::password.eq(hash)))
+            .execute(&self.conn);
+        match ret {
+            Ok(_) => Ok(()),
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Err(UserError::UserAlreadyExist)
+            }
+            Err(e) => Err(e.into()),
         }
-        self.users.insert(user.user.name.clone(), user);
-        Ok(())
     }
 
-    fn login(&mut self, user: &WithPassword) -> UserResult<(Token, Tm)> {
-        let u = self
-            .users
-            .get(&user.user.name)
-            .ok_or(UserError::LoginFailed)?;
-        if u.password != user.password {
-            return Err(UserError::LoginFailed);
+    /// The password hash of the user, if it exists.
+    fn password(&self, name: &str) -> UserResult<Option<String>> {
+        Ok(users::table
+            .find(name)
+            .select(users::password)
+            .first::<String>(&self.conn)
+            .optional()?)
+    }
+
+    /// Log in a user whose password is checked, replacing its hash by `rehash` if given.
+    fn login(&mut self, name: &str, rehash: Option<&str>) -> UserResult<(Token, Tm)> {
+        if self.get_user(name)?.1 {
+            return Err(UserError::Banned);
+        }
+        if let Some(hash) = rehash {
+            diesel::update(users::table.find(name))
+                .set(users::password.eq(hash))
+                .execute(&self.conn)?;
         }
 
         // check passed
@@ -96,48 +248,629 @@ impl SimpleDB {
             base64::encode(buf)
         };
         let exp = time::now() + Duration::days(TIMEOUT);
-        self.tokens.insert(
-            token.clone(),
-            TokenInfo {
-                name: user.user.name.clone(),
-                expire: exp,
-            },
-        );
+        diesel::insert_into(tokens::table)
+            .values((
+                tokens::token.eq(&token),
+                tokens::name.eq(name),
+                tokens::expire.eq(exp.to_timespec().sec),
+            ))
+            .execute(&self.conn)?;
         Ok((token, exp))
     }
 
-    fn check_token(&self, token: &str) -> UserResult<Username> {
-        match self.tokens.get(token) {
+    fn check_token(&self, token: &str) -> UserResult<User> {
+        let info = tokens::table
+            .find(token)
+            .select((tokens::name, tokens::expire))
+            .first::<(Username, i64)>(&self.conn)
+            .optional()?;
+        match info {
             None => Err(UserError::BadToken),
-            Some(info) => {
-                if info.expire < time::now() {
-                    Err(UserError::BadToken)
-                } else {
-                    Ok(info.name.clone())
+            Some((name, expire)) => {
+                if time::at(Timespec::new(expire, 0)) < time::now() {
+                    return Err(UserError::BadToken);
+                }
+                match self.get_user(&name)? {
+                    (_, true) => Err(UserError::Banned),
+                    (user, false) => Ok(user),
                 }
             }
         }
     }
 
     fn logout(&mut self, token: &str) -> UserResult<()> {
-        match self.tokens.get(token) {
-            None => Err(UserError::BadToken),
-            Some(_) => {
-                self.tokens.remove(token);
-                Ok(())
-            }
+        match diesel::delete(tokens::table.find(token)).execute(&self.conn)? {
+            0 => Err(UserError::BadToken),
+            _ => Ok(()),
         }
     }
 
     fn set_location(&mut self, name: Username, loc: PixelPos) -> UserResult<()> {
-        self.locations.insert(name, loc);
+        diesel::replace_into(locations::table)
+            .values((
+                locations::name.eq(name),
+                locations::x.eq(loc.x),
+                locations::y.eq(loc.y),
+            ))
+            .execute(&self.conn)?;
         Ok(())
     }
 
     fn get_location(&self, name: &str) -> UserResult<PixelPos> {
-        match self.locations.get(name) {
+        let loc = locations::table
+            .find(name)
+            .select((locations::x, locations::y))
+            .first::<(i64, i64)>(&self.conn)
+            .optional()?;
+        match loc {
             None => Ok(PixelPos::default()),
-            Some(loc) => Ok(*loc),
+            Some((x, y)) => Ok(PixelPos { x, y }),
+        }
+    }
+
+    /// The user and whether it is banned.
+    fn get_user(&self, name: &str) -> UserResult<(User, bool)> {
+        let (role, banned) = users::table
+            .find(name)
+            .select((users::role, users::banned))
+            .first::<(i32, bool)>(&self.conn)
+            .optional()?
+            .ok_or(UserError::UserNotFound)?;
+        let user = User {
+            name: name.to_owned(),
+            role: UserRole::from_i32(role).unwrap_or_default(),
+        };
+        Ok((user, banned))
+    }
+
+    fn set_user_role(&mut self, name: &str, role: UserRole) -> UserResult<()> {
+        match diesel::update(users::table.find(name))
+            .set(users::role.eq(role as i32))
+            .execute(&self.conn)?
+        {
+            0 => Err(UserError::UserNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the user an admin if there is none, return whether it was made one.
+    fn promote_first_admin(&mut self, name: &str) -> UserResult<bool> {
+        self.conn.transaction(|| {
+            let admins = users::table
+                .filter(users::role.eq(UserRole::Admin as i32))
+                .count()
+                .get_result::<i64>(&self.conn)?;
+            if admins > 0 {
+                return Ok(false);
+            }
+            diesel::update(users::table.find(name))
+                .set(users::role.eq(UserRole::Admin as i32))
+                .execute(&self.conn)?;
+            Ok(true)
+        })
+    }
+
+    fn set_banned(&mut self, name: &str, banned: bool) -> UserResult<()> {
+        self.conn.transaction(|| {
+            let cnt = diesel::update(users::table.find(name))
+                .set(users::banned.eq(banned))
+                .execute(&self.conn)?;
+            if cnt == 0 {
+                return Err(UserError::UserNotFound);
+            }
+            if banned {
+                diesel::delete(tokens::table.filter(tokens::name.eq(name))).execute(&self.conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn exists(&self, name: &str) -> UserResult<bool> {
+        let cnt = users::table
+            .find(name)
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        Ok(cnt > 0)
+    }
+
+    fn send_request(&mut self, mut request: LockRequest) -> UserResult<LockRequest> {
+        self.conn.transaction(|| {
+            let pending = lock_requests::table
+                .select(lock_requests::id)
+                .filter(lock_requests::kind.eq(request.kind as i32))
+                .filter(lock_requests::sender.eq(&request.sender))
+                .filter(lock_requests::recipient.eq(&request.recipient))
+                .filter(lock_requests::x.eq(request.x))
+                .filter(lock_requests::y.eq(request.y))
+                .filter(lock_requests::w.eq(request.w as i32))
+                .filter(lock_requests::h.eq(request.h as i32))
+                .first::<i32>(&self.conn)
+                .optional()?;
+            if let Some(id) = pending {
+                request.id = id;
+                return Ok(request);
+            }
+            let sent = lock_requests::table
+                .filter(lock_requests::sender.eq(&request.sender))
+                .count()
+                .get_result::<i64>(&self.conn)?;
+            if sent >= MAX_SENT {
+                return Err(UserError::TooManyRequests);
+            }
+            let cnt = lock_requests::table
+                .filter(lock_requests::recipient.eq(&request.recipient))
+                .count()
+                .get_result::<i64>(&self.conn)?;
+            if cnt >= MAX_INBOX {
+                return Err(UserError::InboxFull);
+            }
+            diesel::insert_into(lock_requests::table)
+                .values((
+                    lock_requests::kind.eq(request.kind as i32),
+                    lock_requests::sender.eq(&request.sender),
+                    lock_requests::recipient.eq(&request.recipient),
+                    lock_requests::x.eq(request.x),
+                    lock_requests::y.eq(request.y),
+                    lock_requests::w.eq(request.w as i32),
+                    lock_requests::h.eq(request.h as i32),
+                ))
+                .execute(&self.conn)?;
+            request.id = lock_requests::table
+                .select(lock_requests::id)
+                .order(lock_requests::id.desc())
+                .first(&self.conn)?;
+            Ok(request)
+        })
+    }
+
+    fn inbox(&self, recipient: &str) -> UserResult<Vec<LockRequest>> {
+        let rows = lock_requests::table
+            .filter(lock_requests::recipient.eq(recipient))
+            .order(lock_requests::id)
+            .load::<RequestRow>(&self.conn)?;
+        Ok(rows.into_iter().filter_map(request_from_row).collect())
+    }
+
+    fn get_request(&self, id: i32) -> UserResult<LockRequest> {
+        lock_requests::table
+            .find(id)
+            .first::<RequestRow>(&self.conn)
+            .optional()?
+            .and_then(request_from_row)
+            .ok_or(UserError::RequestNotFound)
+    }
+
+    fn del_request(&mut self, id: i32) -> UserResult<()> {
+        match diesel::delete(lock_requests::table.find(id)).execute(&self.conn)? {
+            0 => Err(UserError::RequestNotFound),
+            _ => Ok(()),
         }
     }
+
+    fn role(&self, team: &str, name: &str) -> UserResult<Option<Role>> {
+        let role = members::table
+            .find((team, name))
+            .select(members::role)
+            .first::<i32>(&self.conn)
+            .optional()?;
+        Ok(role.and_then(Role::from_i32))
+    }
+
+    /// Role of `name` in the team, which must be at least `min`.
+    fn check_role(&self, team: &str, name: &str, min: Role) -> UserResult<Role> {
+        match self.role(team, name)? {
+            Some(role) if role >= min => Ok(role),
+            Some(_) => Err(UserError::PermissionDenied),
+            None => {
+                let cnt = teams::table
+                    .find(team)
+                    .count()
+                    .get_result::<i64>(&self.conn)?;
+                if cnt > 0 {
+                    Err(UserError::PermissionDenied)
+                } else {
+                    Err(UserError::TeamNotFound)
+                }
+            }
+        }
+    }
+
+    fn new_team(&mut self, owner: &str, team: Team) -> UserResult<()> {
+        self.conn.transaction(|| {
+            let ret = diesel::insert_into(teams::table)
+                .values(teams::name.eq(&team.name))
+                .execute(&self.conn);
+            match ret {
+                Ok(_) => (),
+                Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    return Err(UserError::TeamAlreadyExist)
+                }
+                Err(e) => return Err(e.into()),
+            }
+            diesel::insert_into(members::table)
+                .values((
+                    members::team.eq(&team.name),
+                    members::name.eq(owner),
+                    members::role.eq(Role::Owner as i32),
+                ))
+                .execute(&self.conn)?;
+            Ok(())
+        })
+    }
+
+    fn teams_of(&self, name: &str) -> UserResult<Vec<Membership>> {
+        let teams = members::table
+            .filter(members::name.eq(name))
+            .select((members::team, members::role))
+            .order(members::team)
+            .load::<(Teamname, i32)>(&self.conn)?;
+        Ok(teams
+            .into_iter()
+            .filter_map(|(team, role)| {
+                Some(Membership {
+                    team,
+                    role: Role::from_i32(role)?,
+                })
+            })
+            .collect())
+    }
+
+    fn members(&self, team: &str, by: &str) -> UserResult<Vec<Member>> {
+        self.check_role(team, by, Role::Member)?;
+        let members = members::table
+            .filter(members::team.eq(team))
+            .select((members::name, members::role))
+            .order(members::name)
+            .load::<(Username, i32)>(&self.conn)?;
+        Ok(members
+            .into_iter()
+            .filter_map(|(name, role)| {
+                Some(Member {
+                    name,
+                    role: Role::from_i32(role)?,
+                })
+            })
+            .collect())
+    }
+
+    fn invite(&mut self, team: &str, by: &str, name: &str) -> UserResult<()> {
+        self.check_role(team, by, Role::Admin)?;
+        if !self.exists(name)? {
+            return Err(UserError::UserNotFound);
+        }
+        if self.role(team, name)?.is_some() {
+            return Err(UserError::AlreadyMember);
+        }
+        diesel::replace_into(invites::table)
+            .values((invites::team.eq(team), invites::name.eq(name)))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn invites(&self, name: &str) -> UserResult<Vec<Teamname>> {
+        Ok(invites::table
+            .filter(invites::name.eq(name))
+            .select(invites::team)
+            .order(invites::team)
+            .load(&self.conn)?)
+    }
+
+    fn answer_invite(&mut self, team: &str, name: &str, accept: bool) -> UserResult<()> {
+        self.conn.transaction(|| {
+            if diesel::delete(invites::table.find((team, name))).execute(&self.conn)? == 0 {
+                return Err(UserError::NotInvited);
+            }
+            if accept {
+                diesel::insert_into(members::table)
+                    .values((
+                        members::team.eq(team),
+                        members::name.eq(name),
+                        members::role.eq(Role::Member as i32),
+                    ))
+                    .execute(&self.conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_member(&mut self, team: &str, by: &str, name: &str) -> UserResult<bool> {
+        self.conn.transaction(|| {
+            if by == name {
+                let role = self.check_role(team, name, Role::Member)?;
+                let cnt = members::table
+                    .filter(members::team.eq(team))
+                    .count()
+                    .get_result::<i64>(&self.conn)?;
+                if role == Role::Owner && cnt > 1 {
+                    return Err(UserError::OwnerLeaving);
+                }
+                if cnt == 1 {
+                    // the last member leaves, drop the team
+                    diesel::delete(invites::table.filter(invites::team.eq(team)))
+                        .execute(&self.conn)?;
+                    diesel::delete(members::table.filter(members::team.eq(team)))
+                        .execute(&self.conn)?;
+                    diesel::delete(teams::table.find(team)).execute(&self.conn)?;
+                    return Ok(true);
+                }
+            } else {
+                let role = self.check_role(team, by, Role::Admin)?;
+                match self.role(team, name)? {
+                    None => return Err(UserError::UserNotFound),
+                    Some(target) if target >= role => return Err(UserError::PermissionDenied),
+                    Some(_) => (),
+                }
+            }
+            diesel::delete(members::table.find((team, name))).execute(&self.conn)?;
+            Ok(false)
+        })
+    }
+
+    fn set_role(&mut self, team: &str, by: &str, name: &str, role: Role) -> UserResult<()> {
+        self.conn.transaction(|| {
+            self.check_role(team, by, Role::Owner)?;
+            if by == name {
+                return Err(UserError::PermissionDenied);
+            }
+            if self.role(team, name)?.is_none() {
+                return Err(UserError::UserNotFound);
+            }
+            if role == Role::Owner {
+                diesel::update(members::table.find((team, by)))
+                    .set(members::role.eq(Role::Admin as i32))
+                    .execute(&self.conn)?;
+            }
+            diesel::update(members::table.find((team, name)))
+                .set(members::role.eq(role as i32))
+                .execute(&self.conn)?;
+            Ok(())
+        })
+    }
+}
+
+/// Columns of `lock_requests`, in order.
+type RequestRow = (i32, i32, Username, Username, i64, i64, i32, i32);
+
+/// Hash a password on the blocking thread pool.
+async fn hash(password: String) -> UserResult<String> {
+    Ok(password::blocking(move || password::hash(&password)).await?)
+}
+
+fn request_from_row(row: RequestRow) -> Option<LockRequest> {
+    let (id, kind, sender, recipient, x, y, w, h) = row;
+    Some(LockRequest {
+        id,
+        kind: RequestKind::from_i32(kind)?,
+        sender,
+        recipient,
+        x,
+        y,
+        w: w as u8,
+        h: h as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, password: &str) -> WithPassword {
+        WithPassword {
+            user: User {
+                name: name.to_owned(),
+                role: UserRole::User,
+            },
+            password: password.to_owned(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_user_db() -> UserResult<()> {
+        let db = UserDB::open(":memory:")?;
+        db.new_user(user("luffbee", "p4_sS-w@.rD")).await?;
+        assert!(db.new_user(user("luffbee", "another")).await.is_err());
+        assert!(db.login(&user("luffbee", "wrong-password")).await.is_err());
+        assert!(db.login(&user("nobody", "p4_sS-w@.rD")).await.is_err());
+        assert!(db.exists("luffbee").await?);
+        assert!(!db.exists("nobody").await?);
+
+        let (token, _) = db.login(&user("luffbee", "p4_sS-w@.rD")).await?;
+        let stored = users::table
+            .select(users::password)
+            .first::<String>(&db.0.lock().conn)?;
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(db.check_token(&token).await?.name, "luffbee");
+
+        assert_eq!(db.get_location("luffbee").await?.x, 0);
+        db.set_location("luffbee".to_owned(), PixelPos { x: 3, y: -4 })
+            .await?;
+        db.set_location("luffbee".to_owned(), PixelPos { x: 5, y: -6 })
+            .await?;
+        let loc = db.get_location("luffbee").await?;
+        assert_eq!((loc.x, loc.y), (5, -6));
+
+        db.logout(&token).await?;
+        assert!(db.check_token(&token).await.is_err());
+        assert!(db.logout(&token).await.is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_roles() -> UserResult<()> {
+        let db = UserDB::open(":memory:")?;
+        db.new_user(user("luffbee", "p4_sS-w@.rD")).await?;
+        assert_eq!(db.get_user("luffbee").await?.role, UserRole::User);
+
+        assert!(db.bootstrap_admin("root", None).await.is_err());
+        assert!(
+            db.bootstrap_admin("root", Some("r00t-p4ss".to_owned()))
+                .await?
+        );
+        let (token, _) = db.login(&user("root", "r00t-p4ss")).await?;
+        assert_eq!(db.check_token(&token).await?.role, UserRole::Admin);
+        // an existing user is only promoted without any admin
+        assert!(!db.bootstrap_admin("luffbee", None).await?);
+        assert_eq!(db.get_user("luffbee").await?.role, UserRole::User);
+        db.set_user_role("root", UserRole::User).await?;
+        assert!(db.bootstrap_admin("luffbee", None).await?);
+        assert_eq!(db.get_user("luffbee").await?.role, UserRole::Admin);
+        // a demoted bootstrap admin stays demoted
+        assert!(!db.bootstrap_admin("root", None).await?);
+        assert_eq!(db.get_user("root").await?.role, UserRole::User);
+        db.set_user_role("luffbee", UserRole::Moderator).await?;
+        assert_eq!(db.get_user("luffbee").await?.role, UserRole::Moderator);
+        assert!(db.set_user_role("nobody", UserRole::Admin).await.is_err());
+
+        // a ban logs the user out and keeps it out
+        let (token, _) = db.login(&user("luffbee", "p4_sS-w@.rD")).await?;
+        db.set_banned("luffbee", true).await?;
+        assert!(db.check_token(&token).await.is_err());
+        match db.login(&user("luffbee", "p4_sS-w@.rD")).await {
+            Err(UserError::Banned) => (),
+            _ => panic!("banned user logged in"),
+        }
+        db.set_banned("luffbee", false).await?;
+        let (token, _) = db.login(&user("luffbee", "p4_sS-w@.rD")).await?;
+        assert_eq!(db.check_token(&token).await?.name, "luffbee");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_lock_requests() -> UserResult<()> {
+        let db = UserDB::open(":memory:")?;
+        let request = |kind, recipient: &str| LockRequest {
+            id: 0,
+            kind,
+            sender: "luffbee".to_owned(),
+            recipient: recipient.to_owned(),
+            x: -3,
+            y: 4,
+            w: 2,
+            h: 255,
+        };
+        let sent = db
+            .send_request(request(RequestKind::Transfer, "sam"))
+            .await?;
+        db.send_request(request(RequestKind::Access, "team:artists"))
+            .await?;
+        // the same request is sent once
+        assert_eq!(
+            db.send_request(request(RequestKind::Transfer, "sam"))
+                .await?
+                .id,
+            sent.id
+        );
+        let inbox = db.inbox("sam").await?;
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(
+            (inbox[0].id, inbox[0].kind),
+            (sent.id, RequestKind::Transfer)
+        );
+        assert_eq!((inbox[0].x, inbox[0].h), (-3, 255));
+        assert_eq!(db.get_request(sent.id).await?.sender, "luffbee");
+
+        db.del_request(sent.id).await?;
+        assert!(db.inbox("sam").await?.is_empty());
+        match db.get_request(sent.id).await {
+            Err(UserError::RequestNotFound) => (),
+            _ => panic!("request not deleted"),
+        }
+
+        for x in 1..MAX_SENT {
+            db.send_request(LockRequest {
+                x,
+                ..request(RequestKind::Access, "sam")
+            })
+            .await?;
+        }
+        match db.send_request(request(RequestKind::Access, "sam")).await {
+            Err(UserError::TooManyRequests) => (),
+            _ => panic!("requests of a sender not limited"),
+        }
+
+        for i in db.inbox("team:artists").await?.len() as i64..MAX_INBOX {
+            db.send_request(LockRequest {
+                sender: format!("user{}", i),
+                ..request(RequestKind::Access, "team:artists")
+            })
+            .await?;
+        }
+        match db
+            .send_request(LockRequest {
+                sender: "tom".to_owned(),
+                ..request(RequestKind::Access, "team:artists")
+            })
+            .await
+        {
+            Err(UserError::InboxFull) => (),
+            _ => panic!("inbox not limited"),
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_teams() -> UserResult<()> {
+        let db = UserDB::open(":memory:")?;
+        for name in &["luffbee", "sam", "tom"] {
+            db.new_user(user(name, "p4_sS-w@.rD")).await?;
+        }
+        let team = || Team {
+            name: "artists".to_owned(),
+        };
+        db.new_team("luffbee", team()).await?;
+        match db.new_team("sam", team()).await {
+            Err(UserError::TeamAlreadyExist) => (),
+            _ => panic!("team created twice"),
+        }
+
+        match db.answer_invite("artists", "sam", true).await {
+            Err(UserError::NotInvited) => (),
+            _ => panic!("joined without an invitation"),
+        }
+        db.invite("artists", "luffbee", "sam").await?;
+        assert_eq!(db.invites("sam").await?, vec!["artists".to_owned()]);
+        db.answer_invite("artists", "sam", true).await?;
+        assert!(db.invites("sam").await?.is_empty());
+        match db.invite("artists", "sam", "tom").await {
+            Err(UserError::PermissionDenied) => (),
+            _ => panic!("a member invited others"),
+        }
+
+        // only admins act on behalf of the team
+        assert_eq!(db.actor("sam".to_owned()).await?.teams, vec!["artists"]);
+        assert!(db.act_as("sam".to_owned(), Some("artists")).await.is_err());
+        db.set_role("artists", "luffbee", "sam", Role::Admin)
+            .await?;
+        assert_eq!(
+            db.act_as("sam".to_owned(), Some("artists")).await?,
+            "team:artists"
+        );
+        match db.act_as("tom".to_owned(), Some("nobody")).await {
+            Err(UserError::TeamNotFound) => (),
+            _ => panic!("acted as a missing team"),
+        }
+
+        db.invite("artists", "sam", "tom").await?;
+        db.answer_invite("artists", "tom", true).await?;
+        assert!(db.remove_member("artists", "tom", "sam").await.is_err());
+        assert!(!db.remove_member("artists", "sam", "tom").await?);
+        assert!(db.actor("tom".to_owned()).await?.teams.is_empty());
+
+        match db.remove_member("artists", "luffbee", "luffbee").await {
+            Err(UserError::OwnerLeaving) => (),
+            _ => panic!("the owner left"),
+        }
+        db.set_role("artists", "luffbee", "sam", Role::Owner)
+            .await?;
+        let members = db.members("artists", "luffbee").await?;
+        let roles: Vec<_> = members.iter().map(|m| (m.name.as_str(), m.role)).collect();
+        assert_eq!(roles, vec![("luffbee", Role::Admin), ("sam", Role::Owner)]);
+        assert!(!db.remove_member("artists", "luffbee", "luffbee").await?);
+        assert!(db.remove_member("artists", "sam", "sam").await?);
+        match db.members("artists", "sam").await {
+            Err(UserError::TeamNotFound) => (),
+            _ => panic!("team not dropped"),
+        }
+        Ok(())
+    }
 }
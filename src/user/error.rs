@@ -5,8 +5,32 @@ pub type UserResult<T> = Result<T, UserError>;
 
 #[derive(Error, Debug)]
 pub enum UserError {
+    #[error("internal error")]
+    Internal(#[from] InternalError),
     #[error("user already exist")]
     UserAlreadyExist,
+    #[error("user not found")]
+    UserNotFound,
+    #[error("team already exist")]
+    TeamAlreadyExist,
+    #[error("team not found")]
+    TeamNotFound,
+    #[error("user is already a member of the team")]
+    AlreadyMember,
+    #[error("no invitation from the team")]
+    NotInvited,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("user is banned")]
+    Banned,
+    #[error("the owner can not leave a team with other members")]
+    OwnerLeaving,
+    #[error("request not found")]
+    RequestNotFound,
+    #[error("inbox of the recipient is full")]
+    InboxFull,
+    #[error("too many requests waiting for an answer")]
+    TooManyRequests,
     #[error("invalid data: {0}")]
     InvalidData(String),
     #[error("username and password not match")]
@@ -21,9 +45,41 @@ impl ResponseError for UserError {
     fn status_code(&self) -> StatusCode {
         use UserError::*;
         match self {
-            UserAlreadyExist => StatusCode::CONFLICT,
+            Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UserAlreadyExist | TeamAlreadyExist | AlreadyMember | OwnerLeaving | InboxFull
+            | TooManyRequests => StatusCode::CONFLICT,
+            UserNotFound | TeamNotFound | NotInvited | RequestNotFound => StatusCode::NOT_FOUND,
+            PermissionDenied | Banned => StatusCode::FORBIDDEN,
             InvalidData(_) => StatusCode::UNPROCESSABLE_ENTITY,
             LoginFailed | NoToken | BadToken => StatusCode::UNAUTHORIZED,
         }
     }
 }
+
+impl From<diesel::result::Error> for UserError {
+    fn from(e: diesel::result::Error) -> Self {
+        InternalError::from(e).into()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum InternalError {
+    #[error("database connection error")]
+    Connection(#[from] diesel::ConnectionError),
+    #[error("database migration error")]
+    Migration(#[from] diesel_migrations::RunMigrationsError),
+    #[error("database error")]
+    Database(#[from] diesel::result::Error),
+    #[error("password hash error")]
+    PasswordHash(#[from] argon2::password_hash::Error),
+    #[error("password hash canceled")]
+    HashCanceled,
+}
+
+impl From<argon2::password_hash::Error> for UserError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        InternalError::from(e).into()
+    }
+}
+
+impl ResponseError for InternalError {}
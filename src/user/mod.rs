@@ -2,15 +2,15 @@ use actix_web::{
     error::Result,
     http::Cookie,
     web,
-    web::{Data, Json},
+    web::{Data, Json, Path},
     HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 
-use crate::paint::PixelPos;
+use crate::paint::{PaintDB, PixelPos};
 
 mod data;
 use data::*;
-pub use data::{User, Username};
+pub use data::{Actor, LockRequest, RequestKind, Teamname, UserRole, Username};
 
 mod db;
 pub use db::UserDB;
@@ -18,20 +18,105 @@ pub use db::UserDB;
 mod error;
 pub use error::{UserError, UserResult};
 
+mod password;
+mod schema;
+
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.route("", web::post().to(register))
-        .service(
-            web::resource("/auth")
-                .route(web::post().to(login))
-                .route(web::delete().to(logout)),
-        )
+    cfg.service(
+        web::resource("")
+            .route(web::get().to(get_user))
+            .route(web::post().to(register)),
+    )
+    .service(
+        web::resource("/auth")
+            .route(web::post().to(login))
+            .route(web::delete().to(logout)),
+    )
+    .service(
+        web::resource("/location")
+            .route(web::get().to(get_location))
+            .route(web::put().to(set_location)),
+    )
+    .service(
+        web::resource("/teams")
+            .route(web::get().to(get_teams))
+            .route(web::post().to(new_team)),
+    )
+    .route("/teams/{team}/members", web::get().to(get_members))
+    .service(
+        web::resource("/teams/{team}/members/{name}")
+            .route(web::put().to(set_role))
+            .route(web::delete().to(remove_member)),
+    )
+    .route("/teams/{team}/invites", web::post().to(invite))
+    .route("/invites", web::get().to(get_invites))
+    .service(
+        web::resource("/invites/{team}")
+            .route(web::post().to(accept_invite))
+            .route(web::delete().to(decline_invite)),
+    );
+}
+
+/// Moderation, mounted under `/admin` of the user scope.
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/users/{name}/role", web::put().to(set_user_role))
         .service(
-            web::resource("/location")
-                .route(web::get().to(get_location))
-                .route(web::put().to(set_location)),
+            web::resource("/users/{name}/ban")
+                .route(web::put().to(ban_user))
+                .route(web::delete().to(unban_user)),
         );
 }
 
+async fn get_user(db: Data<UserDB>, req: HttpRequest) -> Result<Json<User>> {
+    Ok(Json(authorize(&db, &req, UserRole::User).await?))
+}
+
+async fn set_user_role(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    name: Path<(Username,)>,
+    role: Json<NewUserRole>,
+) -> Result<impl Responder> {
+    let admin = authorize(&db, &req, UserRole::Admin).await?;
+    // an admin demoting itself could leave the site without any
+    if admin.name == name.0 {
+        return Err(UserError::PermissionDenied.into());
+    }
+    db.set_user_role(&name.0, role.role).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn ban_user(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    name: Path<(Username,)>,
+) -> Result<impl Responder> {
+    set_banned(&db, &req, &name.0, true).await
+}
+
+async fn unban_user(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    name: Path<(Username,)>,
+) -> Result<impl Responder> {
+    set_banned(&db, &req, &name.0, false).await
+}
+
+/// Moderators only (un)ban users of a lower role.
+async fn set_banned(
+    db: &Data<UserDB>,
+    req: &HttpRequest,
+    name: &str,
+    banned: bool,
+) -> Result<HttpResponse> {
+    let by = authorize(db, req, UserRole::Moderator).await?;
+    if db.get_user(name).await?.role >= by.role {
+        return Err(UserError::PermissionDenied.into());
+    }
+    db.set_banned(name, banned).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
 async fn register(db: Data<UserDB>, user: Json<WithPassword>) -> Result<impl Responder> {
     user.validate()?;
     db.new_user(user.into_inner()).await?;
@@ -73,12 +158,108 @@ async fn get_location(db: Data<UserDB>, req: HttpRequest) -> Result<Json<PixelPo
     Ok(Json(loc))
 }
 
+async fn get_teams(db: Data<UserDB>, req: HttpRequest) -> Result<Json<Vec<Membership>>> {
+    let name = authenticate(&db, &req).await?;
+    Ok(Json(db.teams_of(&name).await?))
+}
+
+async fn new_team(db: Data<UserDB>, req: HttpRequest, team: Json<Team>) -> Result<impl Responder> {
+    team.validate()?;
+    let name = authenticate(&db, &req).await?;
+    db.new_team(&name, team.into_inner()).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_members(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    team: Path<(Teamname,)>,
+) -> Result<Json<Vec<Member>>> {
+    let name = authenticate(&db, &req).await?;
+    Ok(Json(db.members(&team.0, &name).await?))
+}
+
+async fn set_role(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    path: Path<(Teamname, Username)>,
+    role: Json<NewRole>,
+) -> Result<impl Responder> {
+    let name = authenticate(&db, &req).await?;
+    db.set_role(&path.0, &name, &path.1, role.role).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn remove_member(
+    db: Data<UserDB>,
+    pdb: Data<PaintDB>,
+    req: HttpRequest,
+    path: Path<(Teamname, Username)>,
+) -> Result<impl Responder> {
+    let name = authenticate(&db, &req).await?;
+    if db.remove_member(&path.0, &name, &path.1).await? {
+        // a team made again with the same name starts afresh
+        pdb.release_all(&team_owner(&path.0)).await?;
+    }
+    Ok(HttpResponse::Ok())
+}
+
+async fn invite(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    team: Path<(Teamname,)>,
+    user: Json<User>,
+) -> Result<impl Responder> {
+    let name = authenticate(&db, &req).await?;
+    db.invite(&team.0, &name, &user.name).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_invites(db: Data<UserDB>, req: HttpRequest) -> Result<Json<Vec<Teamname>>> {
+    let name = authenticate(&db, &req).await?;
+    Ok(Json(db.invites(&name).await?))
+}
+
+async fn accept_invite(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    team: Path<(Teamname,)>,
+) -> Result<impl Responder> {
+    let name = authenticate(&db, &req).await?;
+    db.answer_invite(&team.0, &name, true).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn decline_invite(
+    db: Data<UserDB>,
+    req: HttpRequest,
+    team: Path<(Teamname,)>,
+) -> Result<impl Responder> {
+    let name = authenticate(&db, &req).await?;
+    db.answer_invite(&team.0, &name, false).await?;
+    Ok(HttpResponse::Ok())
+}
+
 fn get_cookie(req: &HttpRequest) -> Result<Cookie<'static>> {
     Ok(req.cookie(TOKEN_NAME).ok_or(UserError::NoToken)?)
 }
 
 pub async fn authenticate(db: &Data<UserDB>, req: &HttpRequest) -> Result<Username> {
+    Ok(authorize(db, req, UserRole::User).await?.name)
+}
+
+/// Authenticate the user, which must have at least the role.
+pub async fn authorize(db: &Data<UserDB>, req: &HttpRequest, role: UserRole) -> Result<User> {
     let cookie = get_cookie(req)?;
-    let name = db.check_token(cookie.value()).await?;
-    Ok(name)
+    let user = db.check_token(cookie.value()).await?;
+    if user.role < role {
+        return Err(UserError::PermissionDenied.into());
+    }
+    Ok(user)
+}
+
+/// Authenticate the user, along with the teams it belongs to.
+pub async fn authenticate_actor(db: &Data<UserDB>, req: &HttpRequest) -> Result<Actor> {
+    let name = authenticate(db, req).await?;
+    Ok(db.actor(name).await?)
 }
@@ -0,0 +1,116 @@
+use actix_web::{error::BlockingError, web};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
+
+use std::convert::TryFrom;
+
+use super::error::InternalError;
+
+// Passwords are stored as PHC strings, e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+// The algorithm, version and parameters are all kept in the string, so the hash can be
+// upgraded on the next successful login whenever the current settings change.
+
+const ALGORITHM: Algorithm = Algorithm::Argon2id;
+const VERSION: Version = Version::V0x13;
+const MEMORY_KIB: u32 = 19 * 1024;
+const ITERATIONS: u32 = 2;
+const PARALLELISM: u32 = 1;
+
+fn hasher() -> Argon2<'static> {
+    let params =
+        Params::new(MEMORY_KIB, ITERATIONS, PARALLELISM, None).expect("invalid argon2 parameters");
+    Argon2::new(ALGORITHM, VERSION, params)
+}
+
+pub fn hash(password: &str) -> Result<String, InternalError> {
+    let salt = {
+        let buf: &mut [u8] = &mut [0u8; 16];
+        thread_rng().fill(buf);
+        SaltString::encode_b64(buf)?
+    };
+    let hash = hasher().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Check `password` against a stored hash in constant time.
+pub fn verify(password: &str, hash: &str) -> Result<bool, InternalError> {
+    let hash = PasswordHash::new(hash)?;
+    match hasher().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a stored hash was made with outdated algorithm or parameters.
+pub fn needs_rehash(hash: &str) -> bool {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+    if hash.algorithm != ALGORITHM.ident() || hash.version != Some(VERSION.into()) {
+        return true;
+    }
+    match Params::try_from(&hash) {
+        Ok(params) => {
+            params.m_cost() != MEMORY_KIB
+                || params.t_cost() != ITERATIONS
+                || params.p_cost() != PARALLELISM
+        }
+        Err(_) => true,
+    }
+}
+
+/// Hash of a random password, verified against when the user does not exist
+/// so that a failed login takes the same time either way.
+pub fn dummy_hash() -> &'static str {
+    lazy_static! {
+        static ref DUMMY: String = hash("canvast-dummy-password").expect("hash dummy password");
+    }
+    &DUMMY
+}
+
+/// Run a hash or a verification on the blocking thread pool,
+/// it takes tens of milliseconds that must not stall the executor.
+pub async fn blocking<F, T>(f: F) -> Result<T, InternalError>
+where
+    F: FnOnce() -> Result<T, InternalError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => InternalError::HashCanceled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_verify() -> Result<(), InternalError> {
+        let h = hash("p4_sS-w@.rD")?;
+        assert!(h.starts_with("$argon2id$v=19$"));
+        assert_ne!(h, hash("p4_sS-w@.rD")?);
+        assert!(verify("p4_sS-w@.rD", &h)?);
+        assert!(!verify("p4_sS-w@.rd", &h)?);
+        assert!(!needs_rehash(&h));
+
+        let old = Argon2::new(
+            Algorithm::Argon2i,
+            VERSION,
+            Params::new(8, 1, 1, None).unwrap(),
+        )
+        .hash_password(
+            b"p4_sS-w@.rD",
+            &SaltString::encode_b64(b"0123456789abcdef")?,
+        )?
+        .to_string();
+        assert!(verify("p4_sS-w@.rD", &old)?);
+        assert!(needs_rehash(&old));
+        assert!(verify("p4_sS-w@.rD", "p4_sS-w@.rD").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+// diesel 1.x `table!` expands to impls inside anonymous consts
+#![allow(non_local_definitions)]
+
+table! {
+    users (name) {
+        name -> Text,
+        password -> Text,
+        role -> Integer,
+        banned -> Bool,
+    }
+}
+
+table! {
+    tokens (token) {
+        token -> Text,
+        name -> Text,
+        expire -> BigInt,
+    }
+}
+
+table! {
+    locations (name) {
+        name -> Text,
+        x -> BigInt,
+        y -> BigInt,
+    }
+}
+
+table! {
+    teams (name) {
+        name -> Text,
+    }
+}
+
+table! {
+    members (team, name) {
+        team -> Text,
+        name -> Text,
+        role -> Integer,
+    }
+}
+
+table! {
+    invites (team, name) {
+        team -> Text,
+        name -> Text,
+    }
+}
+
+table! {
+    lock_requests (id) {
+        id -> Integer,
+        kind -> Integer,
+        sender -> Text,
+        recipient -> Text,
+        x -> BigInt,
+        y -> BigInt,
+        w -> Integer,
+        h -> Integer,
+    }
+}